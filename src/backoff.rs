@@ -0,0 +1,87 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Exponential reconnect delay: `base * 2^attempt`, capped at `max`, with up to 25% random
+/// jitter so the clients are not reconnecting all at once after a broker/Hass restart.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Backoff {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Called once connected, the next failure is retried after `base` again.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .base
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        // RandomState is seeded randomly, good enough for jitter without an extra dependency
+        let random = RandomState::new().build_hasher().finish();
+        delay + delay.mul_f64((random % 1000) as f64 / 4000.0)
+    }
+
+    /// Sleep for the next delay. Returns early when `shutdown` is set.
+    pub async fn wait(&mut self, shutdown: &AtomicBool) {
+        let mut remaining = self.next_delay();
+        while !shutdown.load(Ordering::SeqCst) && !remaining.is_zero() {
+            let step = remaining.min(Duration::from_millis(500));
+            tokio::time::sleep(step).await;
+            remaining -= step;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The delay is between `expected` and `expected` + 25% of jitter.
+    fn assert_delay(delay: Duration, expected: u64) {
+        let expected = Duration::from_secs(expected);
+        assert!(
+            delay >= expected && delay < expected.mul_f64(1.25),
+            "{:?} is not in the jitter range of {:?}",
+            delay,
+            expected
+        );
+    }
+
+    #[test]
+    fn delay_is_doubled_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        for expected in [1, 2, 4, 8, 16, 32, 60, 60] {
+            assert_delay(backoff.next_delay(), expected);
+        }
+        // The attempts are not overflowing the shift
+        for _ in 0..100 {
+            assert_delay(backoff.next_delay(), 60);
+        }
+    }
+
+    #[test]
+    fn reset_starts_from_base() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert_delay(backoff.next_delay(), 1);
+        assert_delay(backoff.next_delay(), 2);
+    }
+}
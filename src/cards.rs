@@ -1,4 +1,4 @@
-#[allow(dead_code)]
+#[allow(dead_code, clippy::enum_variant_names)]
 #[derive(Eq, Hash, PartialEq, Debug, Clone)]
 pub(crate) enum Card {
     Screensaver,
@@ -6,6 +6,13 @@ pub(crate) enum Card {
     CardAlarm,
     CardThermo,
     CardHome,
+    CardGrid,
+    CardEntities,
+    CardMedia,
+    CardPower,
+    CardChart,
+    CardLChart,
+    CardUnlock,
 }
 
 impl From<String> for Card {
@@ -16,6 +23,13 @@ impl From<String> for Card {
             "cardalarm" => Card::CardAlarm,
             "cardthermo" => Card::CardThermo,
             "cardhome" => Card::CardHome,
+            "cardgrid" => Card::CardGrid,
+            "cardentities" => Card::CardEntities,
+            "cardmedia" => Card::CardMedia,
+            "cardpower" => Card::CardPower,
+            "cardchart" => Card::CardChart,
+            "cardlchart" => Card::CardLChart,
+            "cardunlock" => Card::CardUnlock,
             _ => panic!("Invalid string representation for Card enum variant"),
         }
     }
@@ -29,6 +43,13 @@ impl Card {
             Card::CardAlarm => "cardAlarm",
             Card::CardThermo => "cardThermo",
             Card::CardHome => "cardHome",
+            Card::CardGrid => "cardGrid",
+            Card::CardEntities => "cardEntities",
+            Card::CardMedia => "cardMedia",
+            Card::CardPower => "cardPower",
+            Card::CardChart => "cardChart",
+            Card::CardLChart => "cardLChart",
+            Card::CardUnlock => "cardUnlock",
         }
     }
 }
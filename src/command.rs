@@ -1,12 +1,26 @@
 use crate::cards::Card;
-use bytes::Bytes;
-use chrono::{FixedOffset, Timelike, Utc};
 
-use crate::config::schema::Config;
-use crate::utils::{DeviceState, STORED_STATE, WEATHER_COLORS_KEY, WEATHER_KEY};
+use crate::config::schema::{Cards, Config, Device};
+use crate::mqttc::model::alarm::Alarm;
+use crate::mqttc::model::brightness::Brightness;
+use crate::mqttc::model::chart::Chart;
+use crate::mqttc::model::cover::Cover;
+use crate::mqttc::model::entities::Entities;
+use crate::mqttc::model::fan::Fan;
+use crate::mqttc::model::grid::Grid;
+use crate::mqttc::model::light::Light;
+use crate::mqttc::model::media::Media;
+use crate::mqttc::model::power::Power;
+use crate::mqttc::model::screensaver::Screensaver;
+use crate::mqttc::model::select::Select;
+use crate::mqttc::model::thermo::Thermo;
+use crate::mqttc::model::unlock::Unlock;
+use crate::protocol::{PanelMessage, NAVIGATION};
+use crate::state::{self, DeviceState, StateStore};
 
 pub struct Command<'a> {
     pub(crate) config: &'a Config,
+    pub(crate) store: &'a StateStore,
     pub(crate) device_id: &'a str,
 }
 
@@ -16,6 +30,15 @@ pub enum Page {
     ExistScreensaver,
     CardAlarm,
     CardQR,
+    CardThermo,
+    CardHome,
+    CardGrid,
+    CardEntities,
+    CardMedia,
+    CardPower,
+    CardChart,
+    CardLChart,
+    CardUnlock,
 }
 
 impl From<&str> for Page {
@@ -26,6 +49,15 @@ impl From<&str> for Page {
             "existscreensaver" => Self::ExistScreensaver,
             "cardalarm" => Self::CardAlarm,
             "cardqr" => Self::CardQR,
+            "cardthermo" => Self::CardThermo,
+            "cardhome" => Self::CardHome,
+            "cardgrid" => Self::CardGrid,
+            "cardentities" => Self::CardEntities,
+            "cardmedia" => Self::CardMedia,
+            "cardpower" => Self::CardPower,
+            "cardchart" => Self::CardChart,
+            "cardlchart" => Self::CardLChart,
+            "cardunlock" => Self::CardUnlock,
             _ => panic!(
                 "Invalid string representation for Page::{} enum variant",
                 value
@@ -35,25 +67,192 @@ impl From<&str> for Page {
 }
 
 impl<'a> Command<'_> {
-    pub(crate) fn new(config: &'a Config, device_id: &'a str) -> Command<'a> {
-        Command { config, device_id }
+    pub(crate) fn new(
+        config: &'a Config,
+        store: &'a StateStore,
+        device_id: &'a str,
+    ) -> Command<'a> {
+        Command {
+            config,
+            store,
+            device_id,
+        }
     }
 
-    pub fn execute(&self, page: Page) -> Vec<Bytes> {
+    pub fn execute(&self, page: Page) -> Vec<PanelMessage> {
+        // Drawing a page is closing the popup
+        self.store.clear_detail(self.device_id);
         match page {
             Page::Screensaver | Page::Startup => self.screensaver(),
             Page::ExistScreensaver => self.exist_screensaver(),
             Page::CardAlarm => self.card_alarm(),
             Page::CardQR => self.qr_code(),
+            Page::CardThermo => self.card_thermo(),
+            Page::CardHome => self.card_grid(Card::CardHome, self.kept_sub_page(&Card::CardHome)),
+            Page::CardGrid => self.card_grid(Card::CardGrid, self.kept_sub_page(&Card::CardGrid)),
+            Page::CardEntities => self.card_entities(self.kept_sub_page(&Card::CardEntities)),
+            Page::CardMedia => self.card_media(),
+            Page::CardPower => self.card_power(),
+            Page::CardChart => self.card_chart(Card::CardChart),
+            Page::CardLChart => self.card_chart(Card::CardLChart),
+            Page::CardUnlock => self.card_unlock(),
             // _ => {
             //     vec![]
             // }
         }
     }
 
-    fn exist_screensaver(&self) -> Vec<Bytes> {
-        let mut device = DeviceState::get_state(self.device_id);
-        let mut current_page = Page::Screensaver; // this may never be used
+    /// Draw the current page again, used when the panel is back online. The page history is kept.
+    pub fn redraw(&self) -> Vec<PanelMessage> {
+        let Some(page) = self.store.get(self.device_id).page else {
+            return self.screensaver();
+        };
+        let result = match page.current {
+            Card::Screensaver => self.screensaver(),
+            // The unlock has expired meanwhile, the keypad is replacing the card
+            ref card if self.is_locked(card.as_str()) => return self.open(card.as_str()),
+            ref card => self.execute(Page::from(card.as_str())),
+        };
+        let device_state = DeviceState {
+            page: Some(page),
+            ..Default::default()
+        };
+        self.store.update(self.device_id, device_state);
+        result
+    }
+
+    /// Show the card, or the `cardUnlock` keypad first when the card is locked.
+    pub fn open(&self, card: &str) -> Vec<PanelMessage> {
+        self.select_card(card);
+        if !self.is_locked(card) {
+            return self.execute(Page::from(card));
+        }
+        let device_state = DeviceState {
+            unlock_target: Some(card.to_string()),
+            ..Default::default()
+        };
+        self.store.update(self.device_id, device_state);
+        self.card_unlock()
+    }
+
+    /// Show the card at `index` of `Device::get_cards()` from its first sub page, used by the
+    /// navigation as several cards can have the same type.
+    pub fn open_index(&self, index: usize) -> Vec<PanelMessage> {
+        let Some(card) = self.device_cards().get(index).map(|c| c.type_.clone()) else {
+            return vec![];
+        };
+        if let Some(mut page) = self.store.get(self.device_id).page {
+            page.index = index;
+            page.sub_page = 0;
+            self.update_page(page);
+        }
+        self.open(&card)
+    }
+
+    /// Point the page to the first card of that type, unless a card of that type is already
+    /// selected.
+    fn select_card(&self, card: &str) {
+        let Some(mut page) = self.store.get(self.device_id).page else {
+            return;
+        };
+        match self.config.get_card_index(self.device_id, page.index, card) {
+            Some(index) if index != page.index => {
+                page.index = index;
+                self.update_page(page);
+            }
+            _ => {}
+        }
+    }
+
+    /// Configuration of the card displayed as `card`, see `Page::index`.
+    fn current_card(&self, card: &Card) -> Option<Cards> {
+        let index = self.store.get(self.device_id).page.map_or(0, |p| p.index);
+        self.config
+            .get_card_at(self.device_id, index, card.as_str())
+    }
+
+    /// The sub page of the card when it is drawn again, otherwise the first one.
+    fn kept_sub_page(&self, card: &Card) -> usize {
+        self.store
+            .get(self.device_id)
+            .page
+            .filter(|p| p.current == *card)
+            .map_or(0, |p| p.sub_page)
+    }
+
+    fn device_cards(&self) -> Vec<Cards> {
+        self.config
+            .devices
+            .get(self.device_id)
+            .map(Device::get_cards)
+            .unwrap_or_default()
+    }
+
+    fn update_page(&self, page: state::Page) {
+        let device_state = DeviceState {
+            page: Some(page),
+            ..Default::default()
+        };
+        self.store.update(self.device_id, device_state);
+    }
+
+    fn is_locked(&self, card: &str) -> bool {
+        self.config
+            .devices
+            .get(self.device_id)
+            .is_some_and(|device| Unlock::is_locked(self.config, self.store, device, card))
+    }
+
+    /// Answer to `buttonPress2,cardUnlock-{card},cardUnlock-unlock,{code}`, the card is shown
+    /// when the code is right, otherwise the keypad is flashing.
+    pub fn unlock(&self, card: &str, code: &str) -> Vec<PanelMessage> {
+        let Some(device) = self.config.devices.get(self.device_id) else {
+            return vec![];
+        };
+        if self.config.get_card_by_name(self.device_id, card).is_none() {
+            return vec![];
+        }
+        if Unlock::unlock(self.config, self.store, device, card, code) {
+            self.store.clear_unlock_target(self.device_id);
+            self.execute(Page::from(card))
+        } else {
+            vec![Unlock::get_unlock(self.config, device, card, true)]
+        }
+    }
+
+    /// The keypad of the card waiting in `unlock_target`, displayed by the panel as a
+    /// `cardAlarm` page.
+    fn card_unlock(&self) -> Vec<PanelMessage> {
+        self.store.clear_detail(self.device_id);
+        let mut device_state = self.store.get(self.device_id);
+        let (Some(device), Some(card)) = (
+            self.config.devices.get(self.device_id),
+            device_state.unlock_target.clone(),
+        ) else {
+            return self.screensaver();
+        };
+        if !Unlock::is_locked(self.config, self.store, device, &card) {
+            self.store.clear_unlock_target(self.device_id);
+            return self.execute(Page::from(card.as_str()));
+        }
+        if let Some(mut page) = device_state.page.take() {
+            if page.current != Card::CardUnlock {
+                page.previous = page.current;
+                page.current = Card::CardUnlock;
+            }
+            device_state.page = Some(page);
+        }
+        self.store.update(self.device_id, device_state);
+
+        vec![
+            PanelMessage::PageType(Card::CardAlarm.as_str().to_string()),
+            Unlock::get_unlock(self.config, device, &card, false),
+        ]
+    }
+
+    fn exist_screensaver(&self) -> Vec<PanelMessage> {
+        let mut device = self.store.get(self.device_id);
+        let mut current_page = Card::Screensaver.as_str(); // this may never be used
         if let Some(mut page) = device.page.take() {
             if page.current == page.previous && page.current == Card::Screensaver {
                 if let Some(first_card) = self
@@ -66,227 +265,479 @@ impl<'a> Command<'_> {
                     .map(|card| card.type_.clone())
                 {
                     page.current = Card::from(first_card);
-                    current_page = Page::from(page.current.as_str());
+                    page.index = 0;
+                    current_page = page.current.as_str();
                 }
             } else {
-                current_page = Page::from(page.clone().previous.as_str());
+                current_page = page.previous.as_str();
             }
 
             device.page = Some(page);
         }
-        DeviceState::read_process_overwrite(self.device_id, device);
-        self.execute(current_page)
+        self.store.update(self.device_id, device);
+        self.open(current_page)
     }
 
-    fn card_alarm(&self) -> Vec<Bytes> {
-        let mut r_page = Bytes::default();
-        let mut r_update = Bytes::default();
-        let mut device = DeviceState::get_state(self.device_id);
+    fn card_alarm(&self) -> Vec<PanelMessage> {
+        let mut device = self.store.get(self.device_id);
         if let Some(mut page) = device.page.take() {
             page.previous = page.current;
             page.current = Card::CardAlarm;
             device.page = Some(page);
         }
-        DeviceState::read_process_overwrite(self.device_id, device.clone());
+        self.store.update(self.device_id, device.clone());
 
-        r_page = format!("pageType~{}", Card::CardAlarm.as_str()).into();
+        let mut result = vec![PanelMessage::PageType(Card::CardAlarm.as_str().to_string())];
         if let Some(alarm) = &device.alarm {
-            let mut icon: (String, u32) = ("".to_string(), 0);
-            let code_arm_required = alarm.code_arm_required.unwrap_or(true); // show by default numkey
-            let mut numkey = true;
-            let mut falshing = false;
-
-            match alarm.state.as_str() {
-                "disarmed" => {
-                    icon = (
-                        self.config
-                            .icons
-                            .get("shield-off")
-                            .map_or('\0', |&c| c)
-                            .to_string(),
-                        3334,
-                    );
-                    if !code_arm_required {
-                        numkey = false;
-                    }
-                }
-                "armed_home" => {
-                    icon = (
-                        self.config
-                            .icons
-                            .get("shield-home")
-                            .map_or('\0', |&c| c)
-                            .to_string(),
-                        55907,
-                    );
-                }
-                "armed_away" => {
-                    icon = (
-                        self.config
-                            .icons
-                            .get("shield-lock")
-                            .map_or('\0', |&c| c)
-                            .to_string(),
-                        55907,
-                    );
-                }
-                "armed_night" => {
-                    icon = (
-                        self.config
-                            .icons
-                            .get("weather-night")
-                            .map_or('\0', |&c| c)
-                            .to_string(),
-                        55907,
-                    );
-                }
-                "armed_vacation" => {
-                    icon = (
-                        self.config
-                            .icons
-                            .get("shield-airplane")
-                            .map_or('\0', |&c| c)
-                            .to_string(),
-                        55907,
-                    );
-                }
-                "pending" | "arming" => {
-                    icon = (
-                        self.config
-                            .icons
-                            .get("shield")
-                            .map_or('\0', |&c| c)
-                            .to_string(),
-                        62848,
-                    );
-                    falshing = true;
-                }
-                "triggered" => {
-                    icon = (
-                        self.config
-                            .icons
-                            .get("bell-ring")
-                            .map_or('\0', |&c| c)
-                            .to_string(),
-                        55907,
-                    );
-                    falshing = true;
-                }
-                _ => {}
-            }
-
-            r_update = format!(
-                "entityUpd~{}~1|1~{}~{}~{}~{}~{}~",
-                alarm.entity,
-                alarm.supported_mode,
-                alarm.icon.0,
-                alarm.icon.1,
-                if !numkey { "disable" } else { "enable" },
-                if falshing { "enable" } else { "disable" },
-            )
-            .into();
+            result.push(Alarm::get_alarm_update(self.config, self.device_id, alarm));
         }
-
-        vec![r_page, r_update]
+        result
     }
-    fn screensaver(&self) -> Vec<Bytes> {
-        let mut device = DeviceState::get_state(self.device_id);
+
+    fn screensaver(&self) -> Vec<PanelMessage> {
+        let mut device = self.store.get(self.device_id);
         if let Some(mut page) = device.page.take() {
             page.previous = page.current;
             page.current = Card::Screensaver;
             device.page = Some(page);
         }
-        DeviceState::read_process_overwrite(self.device_id, device);
-
-        let dt = Utc::now().with_timezone(&FixedOffset::east_opt(2 * 3600).unwrap());
-        let date = dt.format("%A, %d. %B %Y");
-        let time = format!("time~{:0>2}:{:0>2}", dt.hour(), dt.minute());
-        let temp = DeviceState::get_state(self.device_id)
-            .temp
-            .unwrap_or_default();
-        let mut result: Vec<Bytes> = vec![
-            "X".into(),
-            time.into(),
-            format!("date~{}", date).into(),
-            format!(
-                "timeout~{}",
-                self.config
-                    .devices
-                    .get(self.device_id)
-                    .expect("Failed to get device_id.")
+        self.store.update(self.device_id, device);
+
+        let device = self
+            .config
+            .devices
+            .get(self.device_id)
+            .expect("Failed to get device_id.");
+        let device_state = self.store.get(self.device_id);
+        let temp = device_state.temp.unwrap_or_default();
+        let mut result = vec![
+            PanelMessage::Raw("X".to_string()),
+            Screensaver::get_time(device),
+            Screensaver::get_date(device),
+            PanelMessage::Timeout(device.config.timeout_to_screensaver),
+            Brightness::get_dim_mode(self.store, device),
+            PanelMessage::PageType("screensaver".to_string()),
+            PanelMessage::Temperature {
+                icon: self
                     .config
-                    .timeout_to_screensaver
-            )
-            .into(),
-            "dimmode~10~100~6371".into(),
-            "pageType~screensaver".into(),
-            format!(
-                "temperature~{}~{}°C",
-                self.config
                     .icons
                     .get("home-thermometer-outline")
-                    .map_or('\0', |&c| c),
-                temp
-            )
-            .into(),
+                    .map_or('\0', |&c| c)
+                    .to_string(),
+                text: format!("{}°C", temp),
+            },
         ];
-        {
-            let map = STORED_STATE
-                .read()
-                .expect("Failed to acquire read lock on STORED_STATE: Lock is poisoned!");
-            if let Some(weather) = map.get(WEATHER_KEY) {
-                result.push(Bytes::from(weather.clone()));
-            }
-            if let Some(weather_colors) = map.get(WEATHER_COLORS_KEY) {
-                result.push(Bytes::from(weather_colors.clone()));
-            }
-        }
+        result.extend(device_state.weather);
         result
     }
 
-    fn qr_code(&self) -> Vec<Bytes> {
-        let mut device_state = DeviceState::get_state(self.device_id);
+    fn qr_code(&self) -> Vec<PanelMessage> {
+        let mut device_state = self.store.get(self.device_id);
 
-        let mut r_page = Bytes::default();
-        let mut r_update = Bytes::default();
+        let mut result = vec![];
         if let Some(mut page) = device_state.page.take() {
             page.previous = page.current;
             page.current = Card::CardQR;
             // Update current page
-            DeviceState::read_process_overwrite(self.device_id, device_state);
+            self.store.update(self.device_id, device_state);
 
-            r_page = format!("pageType~{}", page.current.as_str()).into();
+            result.push(PanelMessage::PageType(page.current.as_str().to_string()));
 
             if let Some(config_card) = self
                 .config
                 .get_card_by_name(self.device_id, page.current.as_str())
             {
+                let icon = |index: usize| {
+                    self.config
+                        .icons
+                        .get(&config_card.entities[index].icon.clone().unwrap_or_default())
+                        .map_or('\0', |&c| c)
+                        .to_string()
+                };
+                let entity = |index: usize, label: &str| {
+                    [
+                        "text".to_string(),
+                        config_card.entities[index].entity.clone(),
+                        icon(index),
+                        17299.to_string(), // Color
+                        label.to_string(),
+                        config_card.entities[index].name.clone().unwrap_or_default(),
+                    ]
+                };
                 // 0|0 means it's only one element
                 // 1|1 means we have multiple cards
                 // 2|0 is like Up button
-                r_update = format!(
-                    "entityUpd~{}~1|1~{}~text~{}~{}~{}~Name~{}~text~{}~{}~{}~Password~{}",
-                    config_card.title.unwrap_or_default(),
-                    config_card.data.unwrap_or_default(),
-                    config_card.entities[0].entity,
-                    self.config
-                        .icons
-                        .get(&config_card.entities[0].icon.clone().unwrap_or_default())
-                        .map_or('\0', |&c| c), // Icon
-                    17299, //Color
-                    config_card.entities[0].name.clone().unwrap_or_default(),
-                    config_card.entities[1].entity,
-                    self.config
-                        .icons
-                        .get(&config_card.entities[1].icon.clone().unwrap_or_default())
-                        .map_or('\0', |&c| c), // Icon
-                    17299, //Color
-                    config_card.entities[1].name.clone().unwrap_or_default()
-                )
-                .into();
+                result.push(PanelMessage::EntityUpd {
+                    heading: config_card.title.clone().unwrap_or_default(),
+                    navigation: NAVIGATION.to_string(),
+                    items: [
+                        vec![config_card.data.clone().unwrap_or_default()],
+                        entity(0, "Name").to_vec(),
+                        entity(1, "Password").to_vec(),
+                    ]
+                    .concat(),
+                });
+            }
+        }
+        result
+    }
+
+    fn card_thermo(&self) -> Vec<PanelMessage> {
+        let mut device_state = self.store.get(self.device_id);
+        if let Some(mut page) = device_state.page.take() {
+            page.previous = page.current;
+            page.current = Card::CardThermo;
+            device_state.page = Some(page);
+        }
+        self.store.update(self.device_id, device_state.clone());
+
+        let mut result = vec![PanelMessage::PageType(
+            Card::CardThermo.as_str().to_string(),
+        )];
+        if let (Some(entity), Some(device)) = (
+            Thermo::get_climate_entity(self.config, self.device_id),
+            self.config.devices.get(self.device_id),
+        ) {
+            if let Some(thermo) = device_state.entities.get(&entity) {
+                result.push(Thermo::get_thermo(self.config, device, &entity, thermo));
+            }
+        }
+        result
+    }
+
+    /// Answer to `pageOpenDetail,popupThermo,{entity}`. The panel is opening the popup by itself,
+    /// only the detail update is sent back.
+    pub fn thermo_detail(&self, entity: &str) -> Vec<PanelMessage> {
+        self.store
+            .get(self.device_id)
+            .entities
+            .get(entity)
+            .map(|thermo| vec![Thermo::get_thermo_detail(self.config, entity, thermo)])
+            .unwrap_or_default()
+    }
+
+    /// Answer to `pageOpenDetail,{popup},{entity}` for the `popupLight`, `popupShutter` and
+    /// `popupFan` popups. The popup is remembered so it is updated when the entity is changing.
+    pub fn open_detail(&self, entity: &str) -> Vec<PanelMessage> {
+        let (Some(page), Some(detail)) =
+            (self.store.get(self.device_id).page, self.get_detail(entity))
+        else {
+            return vec![];
+        };
+        let device_state = DeviceState {
+            detail: Some((page.current, entity.to_string())),
+            ..Default::default()
+        };
+        self.store.update(self.device_id, device_state);
+        vec![detail]
+    }
+
+    /// The detail popup update of the entity, depending on its domain.
+    pub fn get_detail(&self, entity: &str) -> Option<PanelMessage> {
+        let device_state = self.store.get(self.device_id);
+        let stored = device_state.entities.get(entity)?;
+        match entity.split('.').next()? {
+            "light" => Some(Light::get_light_detail(entity, stored)),
+            "cover" => Some(Cover::get_cover_detail(self.config, entity, stored)),
+            "fan" => Some(Fan::get_fan_detail(entity, stored)),
+            _ => None,
+        }
+    }
+
+    fn card_media(&self) -> Vec<PanelMessage> {
+        let mut device_state = self.store.get(self.device_id);
+        if let Some(mut page) = device_state.page.take() {
+            page.previous = page.current;
+            page.current = Card::CardMedia;
+            device_state.page = Some(page);
+        }
+        self.store.update(self.device_id, device_state.clone());
+
+        let mut result = vec![PanelMessage::PageType(Card::CardMedia.as_str().to_string())];
+        if let Some(device) = self.config.devices.get(self.device_id) {
+            if let Some(update) = Media::get_media(self.config, device, &device_state) {
+                result.push(update);
+            }
+        }
+        result
+    }
+
+    fn card_power(&self) -> Vec<PanelMessage> {
+        let mut device_state = self.store.get(self.device_id);
+        if let Some(mut page) = device_state.page.take() {
+            page.previous = page.current;
+            page.current = Card::CardPower;
+            device_state.page = Some(page);
+        }
+        self.store.update(self.device_id, device_state.clone());
+
+        let mut result = vec![PanelMessage::PageType(Card::CardPower.as_str().to_string())];
+        if let Some(device) = self.config.devices.get(self.device_id) {
+            if let Some(update) = Power::get_power(self.config, device, &device_state) {
+                result.push(update);
+            }
+        }
+        result
+    }
+
+    /// The last known history is displayed right away, the refreshed one is sent once received
+    /// from Hass, see `Chart::get_history_request()`.
+    fn card_chart(&self, card: Card) -> Vec<PanelMessage> {
+        let mut device_state = self.store.get(self.device_id);
+        if let Some(mut page) = device_state.page.take() {
+            page.previous = page.current;
+            page.current = card.clone();
+            device_state.page = Some(page);
+        }
+        self.store.update(self.device_id, device_state.clone());
+
+        let mut result = vec![PanelMessage::PageType(card.as_str().to_string())];
+        if let Some(device) = self.config.devices.get(self.device_id) {
+            if let Some(update) = Chart::get_chart(self.config, device, &device_state, &card) {
+                result.push(update);
+            }
+        }
+        result
+    }
+
+    /// Answer to `pageOpenDetail,popupInSel,{entity}` with the options of the entity.
+    pub fn select_detail(&self, entity: &str) -> Vec<PanelMessage> {
+        let device_state = self.store.get(self.device_id);
+        let Some(stored) = device_state.entities.get(entity) else {
+            return vec![];
+        };
+        match entity.split('.').next().unwrap_or_default() {
+            "media_player" => vec![Media::get_source_detail(entity, stored)],
+            "input_select" | "select" => vec![Select::get_select_detail(entity, stored)],
+            _ => vec![],
+        }
+    }
+
+    /// Both `cardHome` and `cardGrid` are displayed by the panel as a `cardGrid` page.
+    fn card_grid(&self, card: Card, sub_page: usize) -> Vec<PanelMessage> {
+        let mut device_state = self.store.get(self.device_id);
+        if let Some(mut page) = device_state.page.take() {
+            if page.current != card {
+                page.previous = page.current;
+                page.current = card.clone();
+            }
+            page.sub_page = sub_page;
+            device_state.page = Some(page);
+        }
+        self.store.update(self.device_id, device_state.clone());
+
+        let mut result = vec![PanelMessage::PageType(Card::CardGrid.as_str().to_string())];
+        if let (Some(device), Some(config_card)) = (
+            self.config.devices.get(self.device_id),
+            self.current_card(&card),
+        ) {
+            if let Some(update) =
+                Grid::get_grid(self.config, device, &device_state, &config_card, sub_page)
+            {
+                result.push(update);
+            }
+        }
+        result
+    }
+
+    fn card_entities(&self, sub_page: usize) -> Vec<PanelMessage> {
+        let mut device_state = self.store.get(self.device_id);
+        if let Some(mut page) = device_state.page.take() {
+            if page.current != Card::CardEntities {
+                page.previous = page.current;
+                page.current = Card::CardEntities;
             }
+            page.sub_page = sub_page;
+            device_state.page = Some(page);
         }
+        self.store.update(self.device_id, device_state.clone());
+
+        let mut result = vec![PanelMessage::PageType(
+            Card::CardEntities.as_str().to_string(),
+        )];
+        if let (Some(device), Some(config_card)) = (
+            self.config.devices.get(self.device_id),
+            self.current_card(&Card::CardEntities),
+        ) {
+            if let Some(update) =
+                Entities::get_entities(self.config, device, &device_state, &config_card, sub_page)
+            {
+                result.push(update);
+            }
+        }
+        result
+    }
+
+    /// Move to the next/previous sub page of the displayed card.
+    /// Returns `None` when there is no sub page in that direction, so the adjacent card is shown.
+    pub fn sub_page(&self, forward: bool) -> Option<Vec<PanelMessage>> {
+        let device = self.config.devices.get(self.device_id)?;
+        let page = self.store.get(self.device_id).page?;
+        let config_card = self.current_card(&page.current)?;
+        let sub_pages = match page.current {
+            Card::CardEntities => Entities::sub_pages(device, &config_card),
+            ref card if Grid::is_grid(card) => Grid::sub_pages(device, &config_card),
+            _ => return None,
+        };
+        let sub_page = if forward {
+            page.sub_page + 1
+        } else {
+            page.sub_page.checked_sub(1)?
+        };
+        if sub_page >= sub_pages {
+            return None;
+        }
+        self.store.clear_detail(self.device_id);
+        match page.current {
+            Card::CardEntities => Some(self.card_entities(sub_page)),
+            card => Some(self.card_grid(card, sub_page)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICES: &str = r#"
+        panel:
+          module: nspanel
+          id: panel
+          mqtt: { rx_topic: cmnd/panel/CustomSend, tx_topic: tele/panel/RESULT }
+          model: EU
+          config: { timeout_to_screensaver: 20, screensaver_brightness: [], locale: en_US,
+                    timezone: Europe/Bucharest }
+          cards:
+            - type: cardGrid
+              title: Lights
+              entities:
+                - { entity: light.light_1 }
+                - { entity: light.light_2 }
+                - { entity: light.light_3 }
+                - { entity: light.light_4 }
+                - { entity: light.light_5 }
+                - { entity: light.light_6 }
+                - { entity: light.light_7 }
+            - { type: cardQR, title: Wifi, data: "WIFI:T:WPA;S:home;P:secret;;",
+                entities: [ { entity: iText.ssid }, { entity: iText.password } ] }
+            - { type: cardGrid, title: Switches, entities: [ { entity: switch.heater } ] }
+    "#;
+
+    /// Heading and first entity of the page update.
+    fn displayed(messages: &[PanelMessage]) -> (String, String) {
+        messages
+            .iter()
+            .find_map(|message| match message {
+                PanelMessage::EntityUpd { heading, items, .. } => {
+                    Some((heading.clone(), items[1].clone()))
+                }
+                _ => None,
+            })
+            .expect("Missing page update")
+    }
+
+    fn expected(heading: &str, entity: &str) -> (String, String) {
+        (heading.to_string(), entity.to_string())
+    }
+
+    #[test]
+    fn cards_of_the_same_type_are_told_apart() {
+        let config = Config::from_yaml(DEVICES);
+        let store = StateStore::new();
+        store.update("panel", DeviceState::default());
+        let command = Command::new(&config, &store, "panel");
+
+        let lights = command.open(Card::CardGrid.as_str());
+        assert_eq!(displayed(&lights), expected("Lights", "light.light_1"));
+
+        let next = config.get_adjacent_card("panel", 0, true).unwrap();
+        assert_eq!(next, 1);
+        let next = config.get_adjacent_card("panel", next, true).unwrap();
+        let switches = command.open_index(next);
+        assert_eq!(displayed(&switches), expected("Switches", "switch.heater"));
+        assert_eq!(store.get("panel").page.map(|p| p.index), Some(2));
+        assert_eq!(config.get_adjacent_card("panel", 2, true), Some(0));
+        assert_eq!(config.get_adjacent_card("panel", 0, false), Some(2));
+
+        // The displayed card is kept when the page is redrawn or shown again by type
+        assert_eq!(
+            displayed(&command.redraw()),
+            expected("Switches", "switch.heater")
+        );
+        assert_eq!(
+            displayed(&command.open(Card::CardGrid.as_str())),
+            expected("Switches", "switch.heater")
+        );
+    }
+
+    #[test]
+    fn redraw_keeps_the_sub_page() {
+        let config = Config::from_yaml(DEVICES);
+        let store = StateStore::new();
+        store.update("panel", DeviceState::default());
+        let command = Command::new(&config, &store, "panel");
+
+        command.open_index(0);
+        let second = command.sub_page(true).unwrap();
+        assert_eq!(displayed(&second), expected("Lights", "light.light_7"));
+        assert!(command.sub_page(true).is_none());
+
+        assert_eq!(
+            displayed(&command.redraw()),
+            expected("Lights", "light.light_7")
+        );
+        assert_eq!(store.get("panel").page.map(|p| p.sub_page), Some(1));
+
+        // Coming back to the card is starting from its first sub page
+        command.execute(Page::Screensaver);
+        assert_eq!(
+            displayed(&command.execute(Page::ExistScreensaver)),
+            expected("Lights", "light.light_1")
+        );
+    }
+
+    #[test]
+    fn entities_cards_are_told_apart() {
+        let config = Config::from_yaml(
+            r#"
+            panel:
+              module: nspanel
+              id: panel
+              mqtt: { rx_topic: cmnd/panel/CustomSend, tx_topic: tele/panel/RESULT }
+              model: EU
+              config: { timeout_to_screensaver: 20, screensaver_brightness: [], locale: en_US,
+                        timezone: Europe/Bucharest }
+              cards:
+                - { type: cardEntities, title: Living, entities: [ { entity: sensor.living } ] }
+                - type: cardEntities
+                  title: Outside
+                  entities:
+                    - { entity: sensor.temperature }
+                    - { entity: sensor.humidity }
+                    - { entity: sensor.wind }
+                    - { entity: sensor.rain }
+                    - { entity: sensor.pressure }
+            "#,
+        );
+        let store = StateStore::new();
+        store.update("panel", DeviceState::default());
+        let command = Command::new(&config, &store, "panel");
+
+        let outside = command.open_index(1);
+        assert_eq!(
+            displayed(&outside),
+            expected("Outside", "sensor.temperature")
+        );
+        let second = command.sub_page(true).unwrap();
+        assert_eq!(displayed(&second), expected("Outside", "sensor.pressure"));
+        assert_eq!(
+            displayed(&command.redraw()),
+            expected("Outside", "sensor.pressure")
+        );
 
-        vec![r_page, r_update]
+        // The navigation is starting the card from its first sub page
+        let living = command.open_index(0);
+        assert_eq!(displayed(&living), expected("Living", "sensor.living"));
+        assert!(command.sub_page(true).is_none());
     }
 }
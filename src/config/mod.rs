@@ -1 +1,3 @@
 pub mod schema;
+#[cfg(feature = "tls")]
+pub mod tls;
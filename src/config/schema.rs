@@ -1,4 +1,7 @@
 use crate::cards::Card;
+use chrono::format::{Item, StrftimeItems};
+use chrono::Locale;
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
@@ -21,6 +24,10 @@ pub enum Model {
 pub struct Mqtt {
     pub rx_topic: String,
     pub tx_topic: String,
+    /// Tasmota `Topic` of the panel, used for `tele/<topic>/LWT` and `tele/<topic>/STATE`.
+    /// Defaults to the device id.
+    #[serde(default)]
+    pub tasmota_topic: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -29,11 +36,24 @@ pub struct DeviceConfig {
     pub screensaver_brightness: Vec<BrightnessScheduler>,
     pub locale: String,
     pub timezone: String,
+    /// Screensaver date format, weekday and month names are translated using `locale`.
+    #[serde(default = "DeviceConfig::default_date_format")]
+    pub date_format: String,
+    /// Display the time using the 12-hour clock.
+    #[serde(default)]
+    pub time_12h: bool,
+    /// Pin of the cards with `locked: true`, either the plain code or `sha256:{hex digest}`.
+    pub unlock_pin: Option<String>,
+    /// Seconds an unlocked card stays accessible without entering the pin again.
+    #[serde(default = "DeviceConfig::default_unlock_timeout")]
+    pub unlock_timeout: u64,
 }
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BrightnessScheduler {
     pub time: String,
     pub value: u16,
+    /// Optional Hass `input_number` providing the value, `value` is used until it is known.
+    pub entity: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -43,6 +63,16 @@ pub struct Cards {
     pub title: Option<String>,
     pub data: Option<String>,
     pub entities: Vec<Entity>,
+    /// `cardChart`/`cardLChart` history period, in hours.
+    pub hours: Option<u32>,
+    /// `cardChart`/`cardLChart` refresh interval while the page is visible, in seconds.
+    pub refresh: Option<u64>,
+    /// Pin asked by the `cardUnlock` keypad before showing the card, same format as
+    /// `unlock_pin`.
+    pub pin: Option<String>,
+    /// Protect the card with the device `unlock_pin`.
+    #[serde(default)]
+    pub locked: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -50,6 +80,11 @@ pub struct Entity {
     pub entity: String,
     pub name: Option<String>,
     pub icon: Option<String>,
+    /// `cardPower` flow speed factor, applied on the sensor value. A negative factor reverses
+    /// the flow direction.
+    pub speed: Option<f64>,
+    /// Icon color (rgb565), used by `cardPower`.
+    pub color: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -66,6 +101,31 @@ pub struct MqttClient {
     pub user: String,
     #[serde(alias = "client_password")]
     pub password: String,
+    /// Retained `online`/`offline` status of the server, `offline` is sent as Last Will.
+    #[serde(default = "MqttClient::default_status_topic")]
+    pub status_topic: String,
+    /// Home Assistant MQTT discovery prefix.
+    #[serde(default = "MqttClient::default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Base of the topics of the panel entities exposed to Home Assistant.
+    #[serde(default = "MqttClient::default_base_topic")]
+    pub base_topic: String,
+    #[serde(flatten)]
+    pub tls: Tls,
+}
+
+impl MqttClient {
+    fn default_status_topic() -> String {
+        "nspanel_server/status".to_string()
+    }
+
+    fn default_discovery_prefix() -> String {
+        "homeassistant".to_string()
+    }
+
+    fn default_base_topic() -> String {
+        "nspanel_server".to_string()
+    }
 }
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Hass {
@@ -74,6 +134,22 @@ pub struct Hass {
     pub host: String,
     pub port: u16,
     pub token: String,
+    #[serde(flatten)]
+    pub tls: Tls,
+}
+
+/// TLS options of a connection, only used when the `tls` feature is enabled.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Tls {
+    #[serde(rename = "tls", default)]
+    pub enabled: bool,
+    /// PEM file with the CA certificate, the system certificates are used when missing.
+    pub ca_file: Option<String>,
+    /// PEM file with the client certificate followed by its PKCS#8 private key.
+    pub client_cert: Option<String>,
+    /// Accept invalid certificates and host names, eg: self-signed certificates.
+    #[serde(default)]
+    pub insecure: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -101,6 +177,13 @@ impl Config {
                     .cards
                     .iter()
                     .flat_map(|c| c.entities.iter().map(|e| e.entity.clone()))
+                    .chain(
+                        device
+                            .config
+                            .screensaver_brightness
+                            .iter()
+                            .filter_map(|b| b.entity.clone()),
+                    )
                     .collect();
                 (key, res)
             })
@@ -147,34 +230,45 @@ impl Config {
         }
     }
 
-    #[allow(dead_code)]
-    pub fn get_adjacent_card(&self, device_id: &str, card: &str, forward: bool) -> Option<Cards> {
-        if let Some((_, device)) = self
-            .devices
-            .iter()
-            .find(|(key, _)| key.as_str() == device_id)
-        {
-            if let Some((index, _)) = device
-                .get_cards()
-                .iter()
-                .enumerate()
-                .find(|(_, c)| c.type_ == card)
-            {
-                let device_cards = &device.get_cards();
-                let new_index = if forward {
-                    (index + 1) % device_cards.len() // Next index, wrapping around at the end
-                } else {
-                    // Previous index, wrapping around at the beginning
-                    if index == 0 {
-                        device_cards.len() - 1
-                    } else {
-                        (index - 1) % device_cards.len()
-                    }
-                };
-                return Some(device_cards[new_index].clone());
-            }
+    /// Index in `Device::get_cards()` of the card of that type: `index` when it is pointing to
+    /// one, otherwise the first one.
+    pub fn get_card_index(&self, device_id: &str, index: usize, card: &str) -> Option<usize> {
+        let device_cards = self.devices.get(device_id)?.get_cards();
+        match device_cards.get(index) {
+            Some(found) if found.type_ == card => Some(index),
+            _ => device_cards.iter().position(|c| c.type_ == card),
+        }
+    }
+
+    /// The card of that type at `index` of `Device::get_cards()`, see `Config::get_card_index()`.
+    pub fn get_card_at(&self, device_id: &str, index: usize, card: &str) -> Option<Cards> {
+        let index = self.get_card_index(device_id, index, card)?;
+        self.devices.get(device_id)?.get_cards().get(index).cloned()
+    }
+
+    /// Index of the card next to the one at `index` of `Device::get_cards()`.
+    pub fn get_adjacent_card(&self, device_id: &str, index: usize, forward: bool) -> Option<usize> {
+        let count = self.devices.get(device_id)?.get_cards().len();
+        if count == 0 {
+            return None;
         }
-        None
+        let new_index = if forward {
+            (index + 1) % count // Next index, wrapping around at the end
+        } else {
+            // Previous index, wrapping around at the beginning
+            (index + count - 1) % count
+        };
+        Some(new_index)
+    }
+}
+
+impl DeviceConfig {
+    fn default_date_format() -> String {
+        "%A, %d. %B %Y".to_string()
+    }
+
+    fn default_unlock_timeout() -> u64 {
+        300
     }
 }
 
@@ -187,6 +281,38 @@ impl Device {
         })
     }
 
+    /// Tasmota telemetry topic, eg: `tele/nspanel-ds/LWT`.
+    pub fn tele_topic(&self, suffix: &str) -> String {
+        let topic = self.mqtt.tasmota_topic.as_deref().unwrap_or(&self.id);
+        format!("tele/{}/{}", topic, suffix)
+    }
+
+    /// Tasmota status topic, eg: `stat/nspanel-ds/RESULT`.
+    pub fn stat_topic(&self, suffix: &str) -> String {
+        let topic = self.mqtt.tasmota_topic.as_deref().unwrap_or(&self.id);
+        format!("stat/{}/{}", topic, suffix)
+    }
+
+    /// Device timezone, `GMT` when it is not a valid IANA name.
+    pub fn timezone(&self) -> Tz {
+        self.config.timezone.parse().unwrap_or(chrono_tz::Etc::GMT)
+    }
+
+    /// Screensaver date format, the default one when it is not a valid strftime format.
+    pub fn date_format(&self) -> String {
+        let format = &self.config.date_format;
+        if StrftimeItems::new(format).any(|item| item == Item::Error) {
+            DeviceConfig::default_date_format()
+        } else {
+            format.clone()
+        }
+    }
+
+    /// Device locale, `POSIX` (english) when it is not supported.
+    pub fn locale(&self) -> Locale {
+        Locale::try_from(self.config.locale.as_str()).unwrap_or(Locale::POSIX)
+    }
+
     /// Get list of card pages to display without `screensaver`.
     pub fn get_cards(&self) -> Vec<Cards> {
         self.cards
@@ -196,3 +322,20 @@ impl Device {
             .collect()
     }
 }
+
+#[cfg(test)]
+impl Config {
+    /// Configuration of the tests, `devices` is the yaml of the devices as in `config.yaml`.
+    pub fn from_yaml(devices: &str) -> Config {
+        let connectivity = r#"
+            MQTT: { type: mqttc, client_id: test, client_host: localhost, client_port: 1883,
+                    client_user: user, client_password: password }
+            hass: { type: hass, host: localhost, port: 8123, token: token }
+        "#;
+        Config {
+            connectivity: serde_yaml::from_str(connectivity).expect("Invalid connectivity"),
+            devices: serde_yaml::from_str(devices).expect("Invalid devices"),
+            icons: BTreeMap::new(),
+        }
+    }
+}
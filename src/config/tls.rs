@@ -0,0 +1,25 @@
+use crate::config::schema::Tls;
+use std::{fs, io};
+use tokio_native_tls::native_tls::{Certificate, Identity, TlsConnector};
+
+impl Tls {
+    /// Build the TLS connector shared by the Mqtt and Hass connections.
+    pub fn connector(&self) -> io::Result<TlsConnector> {
+        let mut builder = TlsConnector::builder();
+        if let Some(ca_file) = &self.ca_file {
+            let ca = Certificate::from_pem(&fs::read(ca_file)?).map_err(io::Error::other)?;
+            builder.add_root_certificate(ca);
+        }
+        if let Some(client_cert) = &self.client_cert {
+            let pem = fs::read(client_cert)?;
+            let identity = Identity::from_pkcs8(&pem, &pem).map_err(io::Error::other)?;
+            builder.identity(identity);
+        }
+        if self.insecure {
+            builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+        builder.build().map_err(io::Error::other)
+    }
+}
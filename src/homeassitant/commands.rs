@@ -0,0 +1,157 @@
+use crate::homeassitant::entities::EntityState;
+use crate::homeassitant::events::{EntitiesEvent, ResultError};
+use crate::protocol::PanelEvent;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+/// Messages sent from the panels (mqtt side) to Hass.
+#[derive(Debug, Clone)]
+pub enum HassCommand {
+    CallService(ServiceCall),
+    FireEvent(FireEvent),
+    History(HistoryRequest),
+}
+
+/// Messages sent from Hass to the panels (mqtt side).
+#[derive(Debug, Clone)]
+pub enum HassUpdate {
+    /// `subscribe_entities` event, holding the snapshot or the changes of the entities.
+    Entities(EntitiesEvent),
+    /// `get_states` result filtered to the device entities, only used for the entities
+    /// missing from the `subscribe_entities` snapshot.
+    States(BTreeMap<String, EntityState>),
+    /// A service call requested by the panel was rejected by Hass.
+    ServiceFailed(ServiceCall, ResultError),
+    /// Numeric history of an entity, as (unix time, value).
+    History(String, Vec<(f64, f64)>),
+}
+
+/// A Hass `fire_event` websocket command.
+#[derive(Debug, Clone)]
+pub struct FireEvent {
+    pub event_type: String,
+    pub event_data: Map<String, Value>,
+}
+
+impl FireEvent {
+    /// `nspanel_event` fired for each event received from a panel, so automations can react
+    /// to the panel interactions.
+    /// * Event data
+    /// ```
+    /// {"device_id": "nspanel-ds", "page": "cardGrid", "event": "buttonPress2",
+    ///  "args": {"entity": "light.living", "action": "OnOff", "value": "1"}}
+    /// ```
+    pub fn panel_event(device_id: &str, page: &str, event: &PanelEvent) -> Self {
+        let (name, args) = match event {
+            PanelEvent::Startup { version, model } => {
+                ("startup", json!({"version": version, "model": model}))
+            }
+            PanelEvent::SleepReached { page } => ("sleepReached", json!({"page": page})),
+            PanelEvent::ButtonPress2 {
+                entity,
+                action,
+                value,
+            } => (
+                "buttonPress2",
+                json!({"entity": entity, "action": action, "value": value}),
+            ),
+            PanelEvent::PageOpenDetail { popup, entity } => {
+                ("pageOpenDetail", json!({"popup": popup, "entity": entity}))
+            }
+            PanelEvent::Button { button, action } => {
+                ("button", json!({"button": button, "action": action}))
+            }
+        };
+        let mut event_data = Map::new();
+        event_data.insert("device_id".to_string(), json!(device_id));
+        event_data.insert("page".to_string(), json!(page));
+        event_data.insert("event".to_string(), json!(name));
+        event_data.insert("args".to_string(), args);
+        Self {
+            event_type: "nspanel_event".to_string(),
+            event_data,
+        }
+    }
+
+    /// Websocket message for this event.
+    pub fn to_message(&self, id: u64) -> String {
+        json!({
+            "id": id,
+            "type": "fire_event",
+            "event_type": self.event_type,
+            "event_data": self.event_data,
+        })
+        .to_string()
+    }
+}
+
+/// A Hass `history/history_during_period` websocket command, for a single entity.
+#[derive(Debug, Clone)]
+pub struct HistoryRequest {
+    pub entity: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl HistoryRequest {
+    /// Websocket message for this request.
+    pub fn to_message(&self, id: u64) -> String {
+        json!({
+            "id": id,
+            "type": "history/history_during_period",
+            "start_time": self.start.to_rfc3339(),
+            "end_time": self.end.to_rfc3339(),
+            "entity_ids": [self.entity],
+            "minimal_response": true,
+            "no_attributes": true,
+            "significant_changes_only": false,
+        })
+        .to_string()
+    }
+}
+
+/// A Hass `call_service` websocket command.
+/// The `id` is assigned by the Hass connection when the command is sent.
+#[derive(Debug, Clone)]
+pub struct ServiceCall {
+    pub domain: String,
+    pub service: String,
+    pub service_data: Map<String, Value>,
+}
+
+impl ServiceCall {
+    /// New service call targeting the provided entity.
+    pub fn new(domain: &str, service: &str, entity_id: &str) -> Self {
+        let mut service_data = Map::new();
+        service_data.insert("entity_id".to_string(), json!(entity_id));
+        Self {
+            domain: domain.to_string(),
+            service: service.to_string(),
+            service_data,
+        }
+    }
+
+    /// Add an extra field to `service_data`.
+    pub fn with(mut self, key: &str, value: impl Into<Value>) -> Self {
+        self.service_data.insert(key.to_string(), value.into());
+        self
+    }
+
+    /// `{domain}.{service}`, used for logging.
+    pub fn name(&self) -> String {
+        format!("{}.{}", self.domain, self.service)
+    }
+
+    /// Websocket message for this service call.
+    pub fn to_message(&self, id: u64) -> String {
+        json!({
+            "id": id,
+            "type": "call_service",
+            "domain": self.domain,
+            "service": self.service,
+            "service_data": self.service_data,
+        })
+        .to_string()
+    }
+}
@@ -0,0 +1,79 @@
+use crate::homeassitant::events::{CompressedState, EntitiesEvent, HassState};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// Last known state of a Hass entity.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EntityState {
+    pub state: String,
+    pub attributes: Map<String, Value>,
+    pub last_changed: f64, // Unix epoch time
+}
+
+impl EntityState {
+    pub fn attribute_str(&self, key: &str) -> Option<&str> {
+        self.attributes.get(key).and_then(Value::as_str)
+    }
+
+    pub fn friendly_name(&self) -> Option<&str> {
+        self.attribute_str("friendly_name")
+    }
+
+    /// `unavailable` entities and the ones restored by Hass at startup have no usable data.
+    pub fn is_available(&self) -> bool {
+        self.state != "unavailable"
+            && self.attributes.get("restored").and_then(Value::as_bool) != Some(true)
+    }
+}
+
+impl From<CompressedState> for EntityState {
+    fn from(value: CompressedState) -> Self {
+        EntityState {
+            state: value.state,
+            attributes: value.attributes,
+            last_changed: value.last_changed,
+        }
+    }
+}
+
+impl From<HassState> for EntityState {
+    fn from(value: HassState) -> Self {
+        EntityState {
+            state: value.state,
+            attributes: value.attributes,
+            last_changed: 0.0,
+        }
+    }
+}
+
+/// Apply a `subscribe_entities` event on the cached entities.
+/// Returns the new state of the added and changed entities.
+pub fn apply_event(
+    entities: &mut BTreeMap<String, EntityState>,
+    event: EntitiesEvent,
+) -> BTreeMap<String, EntityState> {
+    let mut updated = BTreeMap::new();
+    for (entity, state) in event.added {
+        let state = EntityState::from(state);
+        entities.insert(entity.clone(), state.clone());
+        updated.insert(entity, state);
+    }
+    for (entity, diff) in event.changed {
+        let state = entities.entry(entity.clone()).or_default();
+        if let Some(s) = diff.additions.state {
+            state.state = s;
+        }
+        if let Some(lc) = diff.additions.last_changed {
+            state.last_changed = lc;
+        }
+        state.attributes.extend(diff.additions.attributes);
+        for attribute in diff.removals.attributes {
+            state.attributes.remove(&attribute);
+        }
+        updated.insert(entity, state.clone());
+    }
+    for entity in event.removed {
+        entities.remove(&entity);
+    }
+    updated
+}
@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 use std::collections::BTreeMap;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -10,32 +10,104 @@ pub struct RootEvent {
     #[serde(alias = "type")]
     pub type_: String,
     #[serde(alias = "event")]
-    pub event: Event,
+    pub event: EntitiesEvent,
 }
+
+/// `subscribe_entities` event. The first one is a snapshot of all subscribed entities (`a`),
+/// the following ones are only carrying the changes (`c`) and the removed entities (`r`).
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct EntitiesEvent {
+    #[serde(rename = "a", default)]
+    pub added: BTreeMap<String, CompressedState>,
+    #[serde(rename = "c", default)]
+    pub changed: BTreeMap<String, CompressedDiff>,
+    #[serde(rename = "r", default)]
+    pub removed: Vec<String>,
+}
+
+/// Entity state, compressed by Hass.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CompressedState {
+    #[serde(rename = "s")]
+    pub state: String,
+    #[serde(rename = "a", default)]
+    pub attributes: Map<String, Value>,
+    #[serde(rename = "lc", default)]
+    pub last_changed: f64, // Unix epoch time
+}
+
+/// Entity changes, `+` is holding the new/changed values and `-` the removed attributes.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CompressedDiff {
+    #[serde(rename = "+", default)]
+    pub additions: CompressedChange,
+    #[serde(rename = "-", default)]
+    pub removals: CompressedRemoval,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CompressedChange {
+    #[serde(rename = "s")]
+    pub state: Option<String>,
+    #[serde(rename = "a", default)]
+    pub attributes: Map<String, Value>,
+    #[serde(rename = "lc")]
+    pub last_changed: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CompressedRemoval {
+    #[serde(rename = "a", default)]
+    pub attributes: Vec<String>,
+}
+
+/// One element of the `get_states` result.
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Event {
-    #[serde(alias = "a", alias = "c")]
-    pub entities: BTreeMap<String, Value>,
+pub struct HassState {
+    pub entity_id: String,
+    pub state: String,
+    #[serde(default)]
+    pub attributes: Map<String, Value>,
 }
 
+/// Messages of the authentication phase: `auth_required`, `auth_ok` and `auth_invalid`.
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Weather {
-    #[serde(alias = "+")]
-    pub event: WeatherEvent,
+pub struct AuthEvent {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub ha_version: Option<String>,
+    pub message: Option<String>,
 }
 
+/// One state of the `history/history_during_period` result, requested with `minimal_response`.
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct WeatherEvent {
-    #[serde(alias = "s", alias = "state")]
-    pub state: Option<String>,
-    #[serde(alias = "lc")]
-    pub last_changed: f64, // Unix epoch time
-    #[serde(alias = "c")]
-    pub context: String,
+pub struct HistoryState {
+    #[serde(rename = "s")]
+    pub state: String,
+    /// Only sent when it is different from `last_changed`.
+    #[serde(rename = "lu")]
+    pub last_updated: Option<f64>,
+    #[serde(rename = "lc")]
+    pub last_changed: Option<f64>,
+}
+
+/// Response to a request (`subscribe_entities`, `call_service`, ...) having the same `id`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResultEvent {
+    pub id: u64,
+    pub success: bool,
+    pub error: Option<ResultError>,
+    pub result: Option<Value>,
+}
 
-    #[serde(alias = "a")]
-    pub data: Option<WeatherEventData>,
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ResultError {
+    pub code: String,
+    pub message: String,
+    /// Set by Hass for the translated errors, eg: `invalid_code`.
+    pub translation_key: Option<String>,
 }
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WeatherEventData {
     pub temperature: Option<f32>,
@@ -54,6 +126,7 @@ pub struct WeatherEventData {
     pub visibility: Option<f32>,
     pub visibility_unit: Option<String>,
     pub precipitation_unit: Option<String>,
+    #[serde(default)]
     pub forecast: Vec<WeatherForecast>,
     pub friendly_name: Option<String>,
 }
@@ -73,26 +146,3 @@ pub struct WeatherForecast {
     pub wind_speed: Option<f32>,
     pub precipitation: Option<f32>,
 }
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Alarm {
-    #[serde(alias = "+")]
-    pub event: AlarmEvent,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct AlarmEvent {
-    #[serde(alias = "s", alias = "state")]
-    pub state: Option<String>,
-    #[serde(alias = "lc")]
-    pub last_changed: f64, // Unix epoch time
-    #[serde(alias = "a")]
-    pub data: Option<AlarmEventData>,
-}
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct AlarmEventData {
-    pub code_arm_required: Option<bool>,
-    pub code_format: Option<String>,
-    pub friendly_name: Option<String>,
-    pub supported_features: Option<u8>,
-}
@@ -1,63 +1,138 @@
-use crate::config::schema::Config;
-use crate::homeassitant::events::RootEvent;
-use futures::stream::SplitStream;
+use crate::backoff::Backoff;
+use crate::config::schema::{Config, Hass};
+use crate::homeassitant::commands::{HassCommand, HassUpdate, ServiceCall};
+use crate::homeassitant::entities::EntityState;
+use crate::homeassitant::events::{AuthEvent, HassState, HistoryState, ResultEvent, RootEvent};
+use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, StreamExt, TryStreamExt};
 use log::{error, info, trace};
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
 use std::string::String;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::{mpsc, Mutex};
 use tokio::task::JoinHandle;
-use tokio::time::timeout;
-use tokio_tungstenite::tungstenite::Message;
+use tokio::time::{interval, timeout};
+use tokio_tungstenite::tungstenite::{self, Message};
 use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+#[cfg(feature = "tls")]
+use tokio_tungstenite::{connect_async_tls_with_config, Connector};
+
+/// Keepalive ping interval and the time Hass has to answer it.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type WsWrite = SplitSink<WsStream, Message>;
+/// Requests waiting for a `result` message: id -> (device_id, request).
+type PendingRequests = Arc<std::sync::Mutex<HashMap<u64, (String, Request)>>>;
+/// A command received after the connection was lost, sent by the next connection.
+type UnsentCommand = Arc<std::sync::Mutex<Option<(String, HassCommand)>>>;
+
+/// Kind of the request sent to Hass, used to handle its `result` message.
+enum Request {
+    SubscribeEntities,
+    /// Sent once for all devices, the result is split using the device entities.
+    GetStates(BTreeMap<String, Vec<String>>),
+    CallService(ServiceCall),
+    /// `fire_event`, holding the event type.
+    FireEvent(String),
+    /// `history/history_during_period`, holding the entity.
+    History(String),
+    /// Keepalive, holding the time it was sent. Removed by the `pong` message.
+    Ping(Instant),
+}
+
+impl Request {
+    /// Used for logging.
+    fn name(&self) -> String {
+        match self {
+            Request::SubscribeEntities => "subscribe_entities".to_string(),
+            Request::GetStates(_) => "get_states".to_string(),
+            Request::CallService(service) => service.name(),
+            Request::FireEvent(event_type) => format!("fire_event {}", event_type),
+            Request::History(entity) => format!("history {}", entity),
+            Request::Ping(_) => "ping".to_string(),
+        }
+    }
+}
+
+/// Outcome of the authentication phase.
+enum Auth {
+    /// Authenticated, holding the Hass version.
+    Ok(String),
+    /// The token was rejected, retrying won't help until the configuration is changed.
+    Invalid(String),
+    /// The connection was lost or Hass didn't follow the protocol.
+    Failed,
+}
 
 pub fn start_hass(
     config: Arc<Config>,
     shutdown: Arc<AtomicBool>,
     channel: (
-        Sender<(String, String)>,
-        Arc<Mutex<Receiver<(String, String)>>>,
+        Sender<(String, HassUpdate)>,
+        Arc<Mutex<Receiver<(String, HassCommand)>>>,
     ),
 ) -> JoinHandle<()> {
     tokio::spawn(async move {
         let (sender, mut receiver) = mpsc::channel::<String>(10);
         let shutdown_clone = shutdown.clone();
         let shared_map = Arc::new(RwLock::new(HashMap::new()));
+        let unsent: UnsentCommand = Arc::default();
 
         let sender_to_mqtt = channel.0;
         let receiver_from_mqtt = channel.1;
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
 
         while !shutdown_clone.load(Ordering::SeqCst) {
             let cloned_sender = sender.clone();
-            if let Ok((ws_stream, _)) = connect_async(format!(
-                "ws://{}:{}/api/websocket",
-                config.connectivity.hass.host, config.connectivity.hass.port
-            ))
-            .await
-            {
+            let ws_stream = connect(&config.connectivity.hass).await;
+            if let Err(tungstenite::Error::Io(e)) = &ws_stream {
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::Unsupported | io::ErrorKind::InvalidInput
+                ) {
+                    error!(
+                        "HASS - {}. The connection is retried once the configuration is changed.",
+                        e
+                    );
+                    break;
+                }
+            }
+            if let Ok(mut ws_stream) = ws_stream {
+                match authenticate(&mut ws_stream, &config.connectivity.hass).await {
+                    Auth::Ok(ha_version) => {
+                        info!("HASS - Authenticated on Home Assistant {}", ha_version);
+                        backoff.reset();
+                    }
+                    Auth::Invalid(message) => {
+                        error!(
+                            "HASS - Authentication rejected: {}. Check the token in the configuration, \
+                             the connection is retried once the configuration is changed.",
+                            message
+                        );
+                        break;
+                    }
+                    Auth::Failed => {
+                        backoff.wait(&shutdown_clone).await;
+                        continue;
+                    }
+                }
                 let (mut write, read) = ws_stream.split();
-
-                // Authenticate
-                let _ = write
-                    .send(Message::Text(
-                        format!(
-                            r#"{{ "type": "auth", "access_token": "{}" }}"#,
-                            config.connectivity.hass.token
-                        )
-                        .into(),
-                    ))
-                    .await;
+                // Ids are unique per connection, pending requests are dropped on reconnect
+                let pending: PendingRequests = Arc::default();
+                let connected = Arc::new(AtomicBool::new(true));
 
                 let mut seq = 1;
                 // Subscribe for entities state changes
                 let b_tree_entities = config.get_entities();
-                for (key, entities) in b_tree_entities {
+                for (key, entities) in b_tree_entities.clone() {
                     //TODO call a model to obtain interested data in specific format
                     let _ = write
                         .send(Message::Text(format!(
@@ -65,34 +140,56 @@ pub fn start_hass(
                             seq, entities
                         ).into()))
                         .await;
+                    pending
+                        .lock()
+                        .unwrap()
+                        .insert(seq, (key.clone(), Request::SubscribeEntities));
                     let mut map = shared_map.write().unwrap();
                     map.insert(seq.to_string(), key);
                     // increment seq for other messages
                     seq += 1;
                 }
+                // Fallback for the entities missing from the subscription snapshots
+                let _ = write
+                    .send(Message::Text(
+                        format!(r#"{{ "id": {}, "type": "get_states" }}"#, seq).into(),
+                    ))
+                    .await;
+                pending.lock().unwrap().insert(
+                    seq,
+                    (String::default(), Request::GetStates(b_tree_entities)),
+                );
+                seq += 1;
 
                 // Clone the HashMap
                 let cloned_map = shared_map.read().unwrap().clone();
                 // Spawn a task to handle incoming messages
                 tokio::spawn(handle_messages(
                     read,
-                    cloned_sender,
+                    cloned_sender.clone(),
                     shutdown.clone(),
+                    connected.clone(),
                     sender_to_mqtt.clone(),
                     cloned_map,
+                    pending.clone(),
                 ));
 
                 tokio::spawn(handle_messages_from_mqtt(
+                    cloned_sender,
                     shutdown.clone(),
+                    connected.clone(),
                     receiver_from_mqtt.clone(),
+                    unsent.clone(),
+                    write,
+                    seq,
+                    pending,
                 ));
 
                 // This loop listens for any reconnect signals
                 while let Some(msg) = receiver.recv().await {
                     // Logic to handle received messages
                     if msg == "Reconnect" {
-                        info!("HASS - reconnecting on a 5 sec interval.");
-                        thread::sleep(Duration::from_secs(5));
+                        info!("HASS - Connection lost, reconnecting.");
                         break;
                     }
                     if msg == "Shutdown" {
@@ -100,47 +197,293 @@ pub fn start_hass(
                         break;
                     }
                 }
+                // Stop both tasks of this connection, the next one spawns its own.
+                connected.store(false, Ordering::SeqCst);
             } else {
                 error!("HASS - Failed to connect to the WebSocket server");
             }
+            backoff.wait(&shutdown_clone).await;
         }
     })
 }
 
+/// Open the Hass websocket, using `wss://` when TLS is configured. A TLS configuration that
+/// can't be used is returned as an `Unsupported` or `InvalidInput` error, the token is never
+/// sent over `ws://` instead.
+async fn connect(hass: &Hass) -> Result<WsStream, tungstenite::Error> {
+    if hass.tls.enabled {
+        #[cfg(feature = "tls")]
+        {
+            let connector = hass.tls.connector().map_err(|e| {
+                tungstenite::Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unable to configure TLS: {}", e),
+                ))
+            })?;
+            let url = format!("wss://{}:{}/api/websocket", hass.host, hass.port);
+            let (ws_stream, _) = connect_async_tls_with_config(
+                url,
+                None,
+                false,
+                Some(Connector::NativeTls(connector)),
+            )
+            .await?;
+            return Ok(ws_stream);
+        }
+        #[cfg(not(feature = "tls"))]
+        return Err(tungstenite::Error::Io(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TLS is configured, but the `tls` feature is not enabled",
+        )));
+    }
+    let url = format!("ws://{}:{}/api/websocket", hass.host, hass.port);
+    let (ws_stream, _) = connect_async(url).await?;
+    Ok(ws_stream)
+}
+
+/// Run the authentication phase: wait for `auth_required`, send the token, then wait for
+/// `auth_ok` or `auth_invalid`. Nothing else may be sent before `auth_ok`.
+async fn authenticate(ws_stream: &mut WsStream, hass: &Hass) -> Auth {
+    let mut ha_version = String::default();
+    loop {
+        let txt = match timeout(Duration::from_secs(10), ws_stream.next()).await {
+            Ok(Some(Ok(Message::Text(txt)))) => txt,
+            Ok(Some(Ok(Message::Ping(_) | Message::Pong(_)))) => continue,
+            Ok(Some(Ok(msg))) => {
+                error!("HASS - Unexpected message during authentication {:?}", msg);
+                return Auth::Failed;
+            }
+            Ok(Some(Err(e))) => {
+                error!("HASS - Error during authentication {:?}", e);
+                return Auth::Failed;
+            }
+            Ok(None) => {
+                error!("HASS - Connection closed during authentication");
+                return Auth::Failed;
+            }
+            Err(_) => {
+                error!("HASS - Timeout during authentication");
+                return Auth::Failed;
+            }
+        };
+        let auth = match serde_json::from_str::<AuthEvent>(&txt) {
+            Ok(auth) => auth,
+            Err(e) => {
+                error!("HASS - Unable to parse auth message {:?}", e);
+                return Auth::Failed;
+            }
+        };
+        match auth.type_.as_str() {
+            "auth_required" => {
+                ha_version = auth.ha_version.unwrap_or_default();
+                let token = format!(r#"{{ "type": "auth", "access_token": "{}" }}"#, hass.token);
+                if let Err(e) = ws_stream.send(Message::Text(token.into())).await {
+                    error!("HASS - Unable to send auth message {:?}", e);
+                    return Auth::Failed;
+                }
+            }
+            "auth_ok" => return Auth::Ok(auth.ha_version.unwrap_or(ha_version)),
+            "auth_invalid" => return Auth::Invalid(auth.message.unwrap_or_default()),
+            other => {
+                error!("HASS - Unexpected {} message during authentication", other);
+                return Auth::Failed;
+            }
+        }
+    }
+}
+
+/// Forward the commands received from panels to Hass, numbering them after the subscriptions.
+#[allow(clippy::too_many_arguments)]
 async fn handle_messages_from_mqtt(
+    sender: Sender<String>,
     shutdown: Arc<AtomicBool>,
-    mqtt_msg: Arc<Mutex<Receiver<(String, String)>>>,
+    connected: Arc<AtomicBool>,
+    mqtt_msg: Arc<Mutex<Receiver<(String, HassCommand)>>>,
+    unsent: UnsentCommand,
+    mut write: WsWrite,
+    mut seq: u64,
+    pending: PendingRequests,
 ) {
-    while !shutdown.load(Ordering::SeqCst) {
-        let message;
-        // Receive messages from the shared receiver
-        // Adding timeout in case config is changed
-        if let Ok(result) = timeout(Duration::from_secs(5), mqtt_msg.lock().await.recv()).await {
-            trace!("Message from Mqtt: {:?}", result);
-            message = result;
+    let mut ping = interval(PING_INTERVAL);
+    // The first tick is immediate, the first ping is sent after `PING_INTERVAL`
+    ping.tick().await;
+    // Wakes the loop up to notice a shutdown or a lost connection
+    let mut check = interval(Duration::from_secs(1));
+    while !shutdown.load(Ordering::SeqCst) && connected.load(Ordering::SeqCst) {
+        let message = tokio::select! {
+            _ = ping.tick() => {
+                pending
+                    .lock()
+                    .unwrap()
+                    .insert(seq, (String::default(), Request::Ping(Instant::now())));
+                let ping = format!(r#"{{ "id": {}, "type": "ping" }}"#, seq);
+                seq += 1;
+                if let Err(e) = write.send(Message::Text(ping.into())).await {
+                    error!("HASS - Unable to send ping {:?}", e);
+                    reconnect(&sender, &connected).await;
+                    break;
+                }
+                continue;
+            }
+            _ = check.tick() => continue,
+            message = next_command(&mqtt_msg, &unsent, &connected) => message,
+        };
+        trace!("Message from Mqtt: {:?}", message);
+        if let Some((device_id, command)) = message {
+            let (text, request) = match command {
+                HassCommand::CallService(service) => {
+                    (service.to_message(seq), Request::CallService(service))
+                }
+                HassCommand::FireEvent(event) => {
+                    (event.to_message(seq), Request::FireEvent(event.event_type))
+                }
+                HassCommand::History(history) => {
+                    (history.to_message(seq), Request::History(history.entity))
+                }
+            };
+            info!(
+                "HASS - Device_id [{}]; Sending {} with id {}",
+                device_id,
+                request.name(),
+                seq
+            );
+            pending
+                .lock()
+                .unwrap()
+                .insert(seq, (device_id.clone(), request));
+            seq += 1;
+            if let Err(e) = write.send(Message::Text(text.into())).await {
+                error!(
+                    "HASS - Device_id [{}]; Unable to send command {:?}",
+                    device_id, e
+                );
+                reconnect(&sender, &connected).await;
+                break; // The connection is gone, a new task is started on reconnect
+            }
         } else {
-            continue;
+            break; // The channel is closed, or the connection was lost while waiting
         }
-        if let Some((key, value)) = message {
-        } else {
-            break; // Exit the loop if the channel is closed
+    }
+    trace!("Exiting async loop from handle_messages_from_mqtt");
+}
+
+/// Receive the next command from the panels, the one left by the previous connection first.
+/// A command received once the connection is lost is left to the next connection, while the
+/// receiver is still locked so the order of the commands is kept.
+async fn next_command(
+    mqtt_msg: &Mutex<Receiver<(String, HassCommand)>>,
+    unsent: &UnsentCommand,
+    connected: &AtomicBool,
+) -> Option<(String, HassCommand)> {
+    let mut receiver = mqtt_msg.lock().await;
+    let left = unsent.lock().unwrap().take();
+    let message = match left {
+        Some(message) => Some(message),
+        None => receiver.recv().await,
+    };
+    if !connected.load(Ordering::SeqCst) {
+        *unsent.lock().unwrap() = message;
+        return None;
+    }
+    message
+}
+
+/// Ask `start_hass` to reconnect. Only the first task noticing the failure sends it, otherwise
+/// the next connection would be dropped by the late duplicate.
+async fn reconnect(sender: &Sender<String>, connected: &AtomicBool) {
+    if connected.swap(false, Ordering::SeqCst) {
+        let _ = sender.send("Reconnect".to_string()).await;
+    }
+}
+
+/// Log the outcome of a request sent to Hass for the device that requested it.
+/// Rejected service calls are passed back to the device, so the panel can show it.
+/// The `get_states` result is split between the devices.
+fn handle_result(txt: &str, pending: &PendingRequests) -> Vec<(String, HassUpdate)> {
+    let result = match serde_json::from_str::<ResultEvent>(txt) {
+        Ok(result) => result,
+        Err(e) => {
+            error!("HASS - Unable to parse result message {:?}", e);
+            return vec![];
+        }
+    };
+    let Some((device_id, request)) = pending.lock().unwrap().remove(&result.id) else {
+        return vec![];
+    };
+    let name = request.name();
+    if !result.success {
+        let error = result.error.unwrap_or_default();
+        error!(
+            "HASS - Device_id [{}]; {} failed. Reason: {}: {}",
+            device_id, name, error.code, error.message
+        );
+        return match request {
+            Request::CallService(service) => {
+                vec![(device_id, HassUpdate::ServiceFailed(service, error))]
+            }
+            _ => vec![],
+        };
+    }
+    trace!("HASS - Device_id [{}]; {} succeeded", device_id, name);
+    match request {
+        Request::GetStates(devices) => {
+            let states: BTreeMap<String, EntityState> = result
+                .result
+                .and_then(|r| serde_json::from_value::<Vec<HassState>>(r).ok())
+                .unwrap_or_default()
+                .into_iter()
+                .map(|state| (state.entity_id.clone(), EntityState::from(state)))
+                .collect();
+            devices
+                .into_iter()
+                .map(|(device_id, entities)| {
+                    let device_states = entities
+                        .iter()
+                        .filter_map(|e| states.get(e).map(|s| (e.clone(), s.clone())))
+                        .collect();
+                    (device_id, HassUpdate::States(device_states))
+                })
+                .collect()
+        }
+        Request::History(entity) => {
+            let points = result
+                .result
+                .and_then(|mut r| r.get_mut(&entity).map(Value::take))
+                .and_then(|r| serde_json::from_value::<Vec<HistoryState>>(r).ok())
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|state| {
+                    let time = state.last_updated.or(state.last_changed)?;
+                    Some((time, state.state.parse::<f64>().ok()?))
+                })
+                .collect();
+            vec![(device_id, HassUpdate::History(entity, points))]
         }
+        _ => vec![],
     }
-    trace!("Exiting async loop from send_on_event");
 }
 
-pub async fn handle_messages(
+async fn handle_messages(
     ws_stream: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
     sender: Sender<String>,
     shutdown: Arc<AtomicBool>,
-    sender_to_mqtt: Sender<(String, String)>,
+    connected: Arc<AtomicBool>,
+    sender_to_mqtt: Sender<(String, HassUpdate)>,
     shared_map_clone: HashMap<String, String>,
+    pending: PendingRequests,
 ) {
     // Handle incoming messages
     let mut incoming = ws_stream.into_stream();
     loop {
         if shutdown.load(Ordering::SeqCst) {
             let _ = sender.send("Shutdown".to_string()).await;
+            return;
+        }
+        if !connected.load(Ordering::SeqCst) {
+            return; // The sending side failed and already asked for a reconnect
+        }
+        if pong_overdue(&pending) {
+            error!("HASS - No pong received in {:?}", PONG_TIMEOUT);
             break;
         }
         match timeout(Duration::from_secs(1), incoming.next()).await {
@@ -151,14 +494,31 @@ pub async fn handle_messages(
                             Message::Text(txt) => {
                                 info!("Received message: {}", txt);
                                 if txt.contains("\"type\":\"event\"") {
-                                    let json = serde_yaml::from_str::<RootEvent>(&*txt).unwrap();
-                                    // info!(logger, "HASS message serde json {:?}", json);
-                                    if let Some(device_id) =
-                                        shared_map_clone.get(&*json.id.to_string())
-                                    {
-                                        let _ = sender_to_mqtt
-                                            .send((device_id.clone(), txt.to_string()))
-                                            .await;
+                                    match serde_json::from_str::<RootEvent>(&txt) {
+                                        Ok(json) => {
+                                            if let Some(device_id) =
+                                                shared_map_clone.get(&*json.id.to_string())
+                                            {
+                                                let _ = sender_to_mqtt
+                                                    .send((
+                                                        device_id.clone(),
+                                                        HassUpdate::Entities(json.event),
+                                                    ))
+                                                    .await;
+                                            }
+                                        }
+                                        Err(e) => {
+                                            error!("HASS - Unable to parse event message {:?}", e)
+                                        }
+                                    }
+                                } else if txt.contains("\"type\":\"result\"") {
+                                    for update in handle_result(&txt, &pending) {
+                                        let _ = sender_to_mqtt.send(update).await;
+                                    }
+                                } else if txt.contains("\"type\":\"pong\"") {
+                                    let pong = serde_json::from_str::<Value>(&txt);
+                                    if let Some(id) = pong.ok().and_then(|v| v["id"].as_u64()) {
+                                        pending.lock().unwrap().remove(&id);
                                     }
                                 }
 
@@ -172,7 +532,6 @@ pub async fn handle_messages(
                             }
                             Message::Close(_) => {
                                 info!("HASS - Connection closed.");
-                                let _ = sender.send("Reconnect".to_string()).await;
                                 break;
                             }
                             _ => {}
@@ -184,8 +543,21 @@ pub async fn handle_messages(
                     }
                 }
             }
+            Ok(None) => {
+                info!("HASS - Connection closed.");
+                break;
+            }
             Err(_) => {} // Timeout occurred
-            _ => {}
         }
     }
+    reconnect(&sender, &connected).await;
+}
+
+/// A keepalive ping is waiting for its pong for longer than `PONG_TIMEOUT`.
+fn pong_overdue(pending: &PendingRequests) -> bool {
+    pending
+        .lock()
+        .unwrap()
+        .values()
+        .any(|(_, request)| matches!(request, Request::Ping(sent) if sent.elapsed() > PONG_TIMEOUT))
 }
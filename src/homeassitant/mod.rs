@@ -1,2 +1,4 @@
+pub(crate) mod commands;
+pub(crate) mod entities;
 pub(crate) mod events;
 pub(crate) mod hass;
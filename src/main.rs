@@ -19,16 +19,21 @@ use tokio::sync::{mpsc, Mutex};
 use tokio::task::JoinHandle;
 
 use crate::config::schema::{Config, Connectivity, Device};
+use crate::homeassitant::commands::{HassCommand, HassUpdate};
 use crate::homeassitant::hass::start_hass;
 use crate::mqttc::MqttC;
+use crate::state::StateStore;
 use crate::utils::redact;
 use crate::watcher::notify::FolderWatcher;
 
+mod backoff;
 mod cards;
 mod command;
 mod config;
 mod homeassitant;
 mod mqttc;
+mod protocol;
+mod state;
 mod utils;
 mod watcher;
 
@@ -82,21 +87,23 @@ async fn main() {
 
     futures::executor::block_on(async move {
         let shutdown = Arc::new(AtomicBool::new(false));
-        let (mqqt2hass_sender, mqqt2hass_receiver) = mpsc::channel::<(String, String)>(100);
-        let (hass2mqtt_sender, hass2mqtt_receiver) = mpsc::channel::<(String, String)>(100);
+        let (mqqt2hass_sender, mqqt2hass_receiver) = mpsc::channel::<(String, HassCommand)>(100);
+        let (hass2mqtt_sender, hass2mqtt_receiver) = mpsc::channel::<(String, HassUpdate)>(100);
         let mqqt2hass_receiver = Arc::new(Mutex::new(mqqt2hass_receiver));
         let hass2mqtt_receiver = Arc::new(Mutex::new(hass2mqtt_receiver));
+        let state = StateStore::new();
 
         info!("Starting Mqtt Client thread.");
         let mut mqtt_handle = start_mqtt(
-            MqttC::new(config.clone()),
+            config.clone(),
+            state.clone(),
             shutdown.clone(),
-            (hass2mqtt_sender.clone(), mqqt2hass_receiver.clone()),
+            (mqqt2hass_sender.clone(), hass2mqtt_receiver.clone()),
         );
         let mut hass_handle = start_hass(
             config.clone(),
             shutdown.clone(),
-            (mqqt2hass_sender.clone(), hass2mqtt_receiver.clone()),
+            (hass2mqtt_sender.clone(), mqqt2hass_receiver.clone()),
         );
 
         if let Err(e) = folder_watcher
@@ -117,17 +124,20 @@ async fn main() {
 
                 shutdown_cloned.store(false, Ordering::SeqCst);
                 let (_, _, config) = get_config();
+                // Pages, entities and schedules are rebuilt for the new configuration
+                state.reset();
                 info!("Starting Mqtt Client thread.");
                 mqtt_handle = start_mqtt(
-                    MqttC::new(config.clone()),
+                    config.clone(),
+                    state.clone(),
                     shutdown.clone(),
-                    (hass2mqtt_sender.clone(), mqqt2hass_receiver.clone()),
+                    (mqqt2hass_sender.clone(), hass2mqtt_receiver.clone()),
                 );
                 info!("Starting HASS Client thread.");
                 hass_handle = start_hass(
                     config.clone(),
                     shutdown.clone(),
-                    (mqqt2hass_sender.clone(), hass2mqtt_receiver.clone()),
+                    (hass2mqtt_sender.clone(), mqqt2hass_receiver.clone()),
                 );
             })
             .await
@@ -141,19 +151,28 @@ async fn main() {
 }
 
 fn start_mqtt(
-    mut mqtt_client: MqttC,
+    config: Arc<Config>,
+    store: StateStore,
     shutdown: Arc<AtomicBool>,
     channel: (
-        Sender<(String, String)>,
-        Arc<Mutex<Receiver<(String, String)>>>,
+        Sender<(String, HassCommand)>,
+        Arc<Mutex<Receiver<(String, HassUpdate)>>>,
     ),
 ) -> JoinHandle<()> {
     let sender_to_hass = channel.0;
     let receiver_from_hass = channel.1;
     tokio::spawn(async move {
-        mqtt_client
-            .subscribe(shutdown, (sender_to_hass, receiver_from_hass))
-            .await;
+        match MqttC::new(config, store) {
+            Ok(mut mqtt_client) => {
+                mqtt_client
+                    .subscribe(shutdown, (sender_to_hass, receiver_from_hass))
+                    .await
+            }
+            Err(e) => error!(
+                "Unable to start the Mqtt client, it is started again once the configuration is changed. Reason: {}",
+                e
+            ),
+        }
     })
 }
 
@@ -0,0 +1,371 @@
+use crate::cards::Card;
+use crate::command::{Command, Page};
+use crate::config::schema::{Config, Device, Model};
+use crate::mqttc::model::brightness::Brightness;
+use crate::protocol::PanelMessage;
+use crate::state::StateStore;
+use log::{error, info, trace};
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::AsyncClient;
+use serde_json::{json, Value};
+
+/// Home Assistant MQTT discovery of the panels. Each device exposes its current page (sensor
+/// and select), whether the screensaver is active (binary_sensor), the screensaver brightness
+/// (number) and a `wake` button.
+/// * Topics, `{base}` is the `base_topic` of the Mqtt connectivity (`nspanel_server` by default)
+/// ```
+/// {base}/{device id}/page              page/set
+/// {base}/{device id}/screensaver
+/// {base}/{device id}/brightness        brightness/set
+///                                      wake
+/// ```
+pub struct Discovery {}
+
+impl Discovery {
+    /// Topics of the commands sent by Hass for the device.
+    pub fn command_topics(config: &Config, device: &Device) -> Vec<String> {
+        ["page/set", "brightness/set", "wake"]
+            .iter()
+            .map(|suffix| Discovery::topic(config, device, suffix))
+            .collect()
+    }
+
+    /// Publish the retained discovery configs of the device entities.
+    pub async fn publish_configs(client: &AsyncClient, config: &Config, device: &Device) {
+        for (topic, payload) in Discovery::configs(config, device) {
+            if let Err(e) = client
+                .publish(topic, QoS::AtLeastOnce, true, payload.to_string())
+                .await
+            {
+                error!(
+                    "Device_id [{}]; Unable to publish discovery config {:?}",
+                    device.id, e
+                );
+            }
+        }
+    }
+
+    /// Discovery config topics and payloads of the device entities.
+    fn configs(config: &Config, device: &Device) -> Vec<(String, Value)> {
+        let prefix = &config.connectivity.mqtt.discovery_prefix;
+        let mut options = vec![Card::Screensaver.as_str().to_string()];
+        options.extend(device.get_cards().into_iter().map(|card| card.type_));
+
+        let entities = [
+            (
+                "sensor",
+                "page",
+                json!({
+                    "name": "Page",
+                    "icon": "mdi:tablet-dashboard",
+                    "state_topic": Discovery::topic(config, device, "page"),
+                }),
+            ),
+            (
+                "select",
+                "page_select",
+                json!({
+                    "name": "Page select",
+                    "icon": "mdi:tablet-dashboard",
+                    "state_topic": Discovery::topic(config, device, "page"),
+                    "command_topic": Discovery::topic(config, device, "page/set"),
+                    "options": options,
+                }),
+            ),
+            (
+                "binary_sensor",
+                "screensaver",
+                json!({
+                    "name": "Screensaver",
+                    "icon": "mdi:monitor-star",
+                    "state_topic": Discovery::topic(config, device, "screensaver"),
+                }),
+            ),
+            (
+                "number",
+                "brightness",
+                json!({
+                    "name": "Screensaver brightness",
+                    "icon": "mdi:brightness-6",
+                    "state_topic": Discovery::topic(config, device, "brightness"),
+                    "command_topic": Discovery::topic(config, device, "brightness/set"),
+                    "min": 0,
+                    "max": 100,
+                    "unit_of_measurement": "%",
+                }),
+            ),
+            (
+                "button",
+                "wake",
+                json!({
+                    "name": "Wake",
+                    "icon": "mdi:gesture-tap",
+                    "command_topic": Discovery::topic(config, device, "wake"),
+                }),
+            ),
+        ];
+
+        let node_id = Discovery::node_id(config, device);
+        entities
+            .into_iter()
+            .map(|(component, object_id, mut payload)| {
+                let fields = payload
+                    .as_object_mut()
+                    .expect("Discovery payload is an object");
+                fields.insert(
+                    "unique_id".to_string(),
+                    Value::from(format!("{}_{}", node_id, object_id)),
+                );
+                fields.insert(
+                    "availability_topic".to_string(),
+                    Value::from(config.connectivity.mqtt.status_topic.as_str()),
+                );
+                fields.insert("device".to_string(), Discovery::device_info(config, device));
+                let topic = format!("{}/{}/{}/{}/config", prefix, component, node_id, object_id);
+                (topic, payload)
+            })
+            .collect()
+    }
+
+    /// Publish the retained states of the device entities.
+    pub async fn publish_states(
+        client: &AsyncClient,
+        config: &Config,
+        store: &StateStore,
+        device: &Device,
+    ) {
+        let device_state = store.get(&device.id);
+        let mut states = vec![];
+        if let Some(page) = device_state.page {
+            let screensaver = if page.current == Card::Screensaver {
+                "ON"
+            } else {
+                "OFF"
+            };
+            states.push(("page", page.current.as_str().to_string()));
+            states.push(("screensaver", screensaver.to_string()));
+        }
+        if let Some(dim) = device_state.dim {
+            states.push(("brightness", dim.to_string()));
+        }
+        for (suffix, state) in states {
+            let _ = client
+                .publish(
+                    Discovery::topic(config, device, suffix),
+                    QoS::AtLeastOnce,
+                    true,
+                    state,
+                )
+                .await;
+        }
+    }
+
+    /// Handle a command sent by Hass, returning the messages for the panel.
+    pub fn process_command(
+        config: &Config,
+        store: &StateStore,
+        device: &Device,
+        topic: &str,
+        payload: &str,
+    ) -> Vec<PanelMessage> {
+        info!(
+            "Device_id [{}]; Hass command {} {}",
+            device.id, topic, payload
+        );
+        let command = Command::new(config, store, &device.id);
+        let current = store.get(&device.id).page.map(|p| p.current);
+        let messages = if topic == Discovery::topic(config, device, "page/set") {
+            let known = payload == Card::Screensaver.as_str()
+                || device.get_cards().iter().any(|card| card.type_ == payload);
+            if known {
+                command.open(payload)
+            } else {
+                error!("Device_id [{}]; Unknown page {}", device.id, payload);
+                vec![]
+            }
+        } else if topic == Discovery::topic(config, device, "brightness/set") {
+            match payload.parse::<f64>() {
+                Ok(value) => {
+                    let dim = value.round().clamp(0.0, 100.0) as u16;
+                    vec![Brightness::set_dim(store, device, dim)]
+                }
+                Err(_) => {
+                    error!("Device_id [{}]; Invalid brightness {}", device.id, payload);
+                    vec![]
+                }
+            }
+        } else if topic == Discovery::topic(config, device, "wake")
+            && current == Some(Card::Screensaver)
+        {
+            command.execute(Page::ExistScreensaver)
+        } else {
+            vec![]
+        };
+        // The state is kept, the panel is redrawn once it is back online
+        if !store.is_online(&device.id) {
+            trace!("Device_id [{}]; Panel is offline, skipping", device.id);
+            return vec![];
+        }
+        messages
+    }
+
+    fn topic(config: &Config, device: &Device, suffix: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            config.connectivity.mqtt.base_topic, device.id, suffix
+        )
+    }
+
+    /// Id of the device in Hass, the base topic keeps the devices of several servers apart.
+    fn node_id(config: &Config, device: &Device) -> String {
+        format!(
+            "{}_{}",
+            config.connectivity.mqtt.base_topic.replace('/', "_"),
+            device.id
+        )
+    }
+
+    fn device_info(config: &Config, device: &Device) -> Value {
+        let model = match device.model {
+            Model::EU => "NSPanel EU",
+            Model::US => "NSPanel US",
+        };
+        json!({
+            "identifiers": [Discovery::node_id(config, device)],
+            "name": device.id,
+            "manufacturer": "Sonoff",
+            "model": model,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::DeviceState;
+
+    const DEVICES: &str = r#"
+        panel:
+          module: nspanel
+          id: panel
+          mqtt: { rx_topic: cmnd/panel/CustomSend, tx_topic: tele/panel/RESULT }
+          model: US
+          config: { timeout_to_screensaver: 20, screensaver_brightness: [], locale: en_US,
+                    timezone: Europe/Bucharest }
+          cards:
+            - { type: cardThermo, title: Bedroom, entities: [ { entity: climate.bedroom } ] }
+    "#;
+
+    fn config(base_topic: &str) -> Config {
+        let mut config = Config::from_yaml(DEVICES);
+        config.connectivity.mqtt.base_topic = base_topic.to_string();
+        config
+    }
+
+    #[test]
+    fn discovery_payloads() {
+        let config = config("nspanel_server");
+        let configs = Discovery::configs(&config, &config.devices["panel"]);
+        let topics: Vec<&str> = configs.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/sensor/nspanel_server_panel/page/config",
+                "homeassistant/select/nspanel_server_panel/page_select/config",
+                "homeassistant/binary_sensor/nspanel_server_panel/screensaver/config",
+                "homeassistant/number/nspanel_server_panel/brightness/config",
+                "homeassistant/button/nspanel_server_panel/wake/config",
+            ]
+        );
+
+        let select = &configs[1].1;
+        assert_eq!(select["unique_id"], "nspanel_server_panel_page_select");
+        assert_eq!(select["state_topic"], "nspanel_server/panel/page");
+        assert_eq!(select["command_topic"], "nspanel_server/panel/page/set");
+        assert_eq!(select["options"], json!(["screensaver", "cardThermo"]));
+        assert_eq!(select["availability_topic"], "nspanel_server/status");
+        assert_eq!(
+            select["device"],
+            json!({
+                "identifiers": ["nspanel_server_panel"],
+                "name": "panel",
+                "manufacturer": "Sonoff",
+                "model": "NSPanel US",
+            })
+        );
+    }
+
+    #[test]
+    fn topics_follow_base_topic() {
+        let config = config("home/panels");
+        let device = &config.devices["panel"];
+        assert_eq!(
+            Discovery::command_topics(&config, device),
+            [
+                "home/panels/panel/page/set",
+                "home/panels/panel/brightness/set",
+                "home/panels/panel/wake",
+            ]
+        );
+        let (topic, wake) = &Discovery::configs(&config, device)[4];
+        assert_eq!(topic, "homeassistant/button/home_panels_panel/wake/config");
+        assert_eq!(wake["unique_id"], "home_panels_panel_wake");
+        assert_eq!(wake["command_topic"], "home/panels/panel/wake");
+    }
+
+    #[test]
+    fn process_command() {
+        let config = config("nspanel_server");
+        let device = &config.devices["panel"];
+        let store = StateStore::new();
+        store.update("panel", DeviceState::default());
+        let process = |topic: &str, payload: &str| {
+            Discovery::process_command(&config, &store, device, topic, payload)
+        };
+
+        let page = process("nspanel_server/panel/page/set", "cardThermo");
+        assert_eq!(page[0], PanelMessage::PageType("cardThermo".to_string()));
+        assert_eq!(
+            store.get("panel").page.map(|p| p.current),
+            Some(Card::CardThermo)
+        );
+        assert!(process("nspanel_server/panel/page/set", "cardGrid").is_empty());
+
+        assert!(matches!(
+            process("nspanel_server/panel/brightness/set", "42.6")[..],
+            [PanelMessage::DimMode { dim: 43, .. }]
+        ));
+        assert_eq!(store.get("panel").dim, Some(43));
+        assert!(process("nspanel_server/panel/brightness/set", "bright").is_empty());
+
+        // Wake only leaves the screensaver
+        assert!(process("nspanel_server/panel/wake", "PRESS").is_empty());
+    }
+
+    #[test]
+    fn offline_panel_is_skipped() {
+        let config = config("nspanel_server");
+        let device = &config.devices["panel"];
+        let store = StateStore::new();
+        store.update(
+            "panel",
+            DeviceState {
+                online: Some(false),
+                ..Default::default()
+            },
+        );
+        let messages = Discovery::process_command(
+            &config,
+            &store,
+            device,
+            "nspanel_server/panel/page/set",
+            "cardThermo",
+        );
+        assert!(messages.is_empty());
+        // The page is shown once the panel is back online
+        assert_eq!(
+            store.get("panel").page.map(|p| p.current),
+            Some(Card::CardThermo)
+        );
+    }
+}
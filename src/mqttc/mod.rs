@@ -1,45 +1,67 @@
-mod model;
+pub(crate) mod discovery;
+pub(crate) mod model;
 
 use bytes::Bytes;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
 use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
+use crate::backoff::Backoff;
 use crate::cards::Card;
-use chrono::{Timelike, Utc};
-use chrono_tz::Tz;
 use futures::stream::FuturesOrdered;
 use futures::StreamExt;
 use log::{error, info, trace};
-use rumqttc::v5::mqttbytes::v5::Packet::Publish;
+use rumqttc::v5::mqttbytes::v5::LastWill;
+use rumqttc::v5::mqttbytes::v5::Packet::{ConnAck, Publish};
 use rumqttc::v5::mqttbytes::QoS;
 use rumqttc::v5::Event::Incoming;
 use rumqttc::v5::{AsyncClient, EventLoop, MqttOptions};
-use serde_json::Value;
+#[cfg(feature = "tls")]
+use rumqttc::{TlsConfiguration, Transport};
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::Mutex;
 use tokio::time::{interval, timeout, Duration};
 
 use crate::command::{Command, Page};
 use crate::config::schema::{Config, Device};
-use crate::homeassitant::events::RootEvent;
+use crate::homeassitant::commands::{FireEvent, HassCommand, HassUpdate, ServiceCall};
+use crate::homeassitant::entities::EntityState;
+use crate::homeassitant::events::ResultError;
+use crate::mqttc::discovery::Discovery;
 use crate::mqttc::model::alarm::Alarm;
+use crate::mqttc::model::brightness::Brightness;
+use crate::mqttc::model::chart::Chart;
+use crate::mqttc::model::cover::Cover;
+use crate::mqttc::model::entities::Entities;
+use crate::mqttc::model::fan::Fan;
+use crate::mqttc::model::grid::Grid;
+use crate::mqttc::model::light::Light;
+use crate::mqttc::model::media::Media;
+use crate::mqttc::model::power::Power;
 use crate::mqttc::model::screensaver::Screensaver;
-use crate::utils;
+use crate::mqttc::model::select::Select;
+use crate::mqttc::model::thermo::Thermo;
+use crate::mqttc::model::unlock::UNLOCK_ACTION;
+use crate::protocol::{PanelEvent, PanelMessage};
+use crate::state::{DeviceState, StateStore};
 
 type Client = (AsyncClient, EventLoop);
 
 pub struct MqttC {
     pub config: Arc<Config>,
     pub client: Client,
+    pub store: StateStore,
     pub running: bool,
 }
 
 impl MqttC {
-    pub fn new(config: Arc<Config>) -> Self {
+    /// Build the Mqtt client, failing when TLS is configured but can't be used. The
+    /// credentials are never sent over a plain connection instead.
+    pub fn new(config: Arc<Config>, store: StateStore) -> io::Result<Self> {
         let mut mqttoptions = MqttOptions::new(
-            "nspanel_server_rust",
+            config.connectivity.mqtt.id.as_str(),
             config.connectivity.mqtt.host.as_str(),
             config.connectivity.mqtt.port,
         );
@@ -47,47 +69,95 @@ impl MqttC {
             &config.connectivity.mqtt.user,
             &config.connectivity.mqtt.password,
         );
-        let client = AsyncClient::new(mqttoptions, 10);
+        mqttoptions.set_last_will(LastWill::new(
+            config.connectivity.mqtt.status_topic.as_str(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+            None,
+        ));
+        if config.connectivity.mqtt.tls.enabled {
+            #[cfg(feature = "tls")]
+            {
+                let connector = config.connectivity.mqtt.tls.connector()?;
+                mqttoptions.set_transport(Transport::tls_with_config(
+                    TlsConfiguration::NativeConnector(connector),
+                ));
+            }
+            #[cfg(not(feature = "tls"))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "TLS is configured, but the `tls` feature is not enabled",
+            ));
+        }
+        // Replies and discovery states are queued from the event loop, before it polls again
+        let client = AsyncClient::new(mqttoptions, 100);
 
-        Self {
+        Ok(Self {
             config,
             client,
+            store,
             running: false,
-        }
+        })
     }
 
     pub async fn subscribe(
         &mut self,
         shutdown: Arc<AtomicBool>,
         channel: (
-            Sender<(String, String)>,
-            Arc<Mutex<Receiver<(String, String)>>>,
+            Sender<(String, HassCommand)>,
+            Arc<Mutex<Receiver<(String, HassUpdate)>>>,
         ),
     ) {
         trace!("Entering in subscribe method");
-        for device in self.config.devices.values() {
-            let _ = self
-                .client
-                .0
-                .subscribe(&device.mqtt.tx_topic, QoS::AtMostOnce)
-                .await;
-            info!(
-                "Mqtt client is register to listen on topic {}",
-                &device.mqtt.tx_topic
-            );
-        }
+        // Incoming topic -> device, used to route the replies to the right panel
+        let devices: HashMap<String, Device> = self
+            .config
+            .devices
+            .values()
+            .map(|device| (device.mqtt.tx_topic.clone(), device.clone()))
+            .collect();
+        // Tasmota physical buttons topic -> device
+        let buttons: HashMap<String, Device> = self
+            .config
+            .devices
+            .values()
+            .map(|device| (device.stat_topic("RESULT"), device.clone()))
+            .collect();
+        // Tasmota LWT and STATE topics -> device, used to track the panels availability
+        let tele: HashMap<String, Device> = self
+            .config
+            .devices
+            .values()
+            .flat_map(|device| {
+                ["LWT", "STATE"].map(|suffix| (device.tele_topic(suffix), device.clone()))
+            })
+            .collect();
+        // Hass discovery command topics -> device
+        let discovery: HashMap<String, Device> = self
+            .config
+            .devices
+            .values()
+            .flat_map(|device| {
+                Discovery::command_topics(&self.config, device)
+                    .into_iter()
+                    .map(|topic| (topic, device.clone()))
+            })
+            .collect();
 
         let sender_to_hass = channel.0;
         let receiver_from_hass = channel.1;
 
         let publisher = self.client.0.clone();
         let config = self.config.clone();
+        let store = self.store.clone();
         let shutdown_cloned = shutdown.clone();
 
         let hass_changes_future = async move {
             MqttC::send_on_event(
                 publisher,
                 config.as_ref(),
+                &store,
                 shutdown_cloned,
                 receiver_from_hass,
             )
@@ -95,26 +165,82 @@ impl MqttC {
         };
         let publisher = self.client.0.clone();
         let config = self.config.clone();
+        let store = self.store.clone();
         let shutdown_cloned = shutdown.clone();
+        let sender_cloned = sender_to_hass.clone();
         let ticker_future = async move {
-            MqttC::send_periodic_message(publisher, config.as_ref(), shutdown_cloned).await;
+            MqttC::send_periodic_message(
+                publisher,
+                config.as_ref(),
+                &store,
+                shutdown_cloned,
+                sender_cloned,
+            )
+            .await;
         };
 
         let mqtt_handling = async move {
+            let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
             while !shutdown.load(Ordering::SeqCst) {
                 // MQTT event handling code goes here
                 let event = timeout(Duration::from_secs(1), self.client.1.poll()).await;
                 match &event {
                     Ok(Ok(e)) => {
                         match e {
+                            Incoming(ConnAck(ack)) => {
+                                info!("Mqtt connected, session present: {}", ack.session_present);
+                                backoff.reset();
+                                // Not awaited here, the requests are only sent while polling
+                                tokio::spawn(MqttC::on_connect(
+                                    self.client.0.clone(),
+                                    self.config.clone(),
+                                    self.store.clone(),
+                                    ack.session_present,
+                                ));
+                            }
                             Incoming(Publish(p)) => {
                                 info!("Mqtt event {:?}", p);
                                 let topic = std::str::from_utf8(p.topic.deref())
                                     .expect("Unable to get topic");
                                 let payload = std::str::from_utf8(p.payload.deref())
                                     .expect("Unable to get payload");
-                                let device_id = &topic[3..topic.len()];
-                                let tx = self.commands_matching(device_id, payload);
+                                let (device, tx) = if let Some(device) = devices.get(topic) {
+                                    let event = match PanelEvent::from_payload(payload) {
+                                        Ok(event) => event,
+                                        Err(e) => {
+                                            error!(
+                                                "Device_id [{}]; Unable to parse payload {}",
+                                                device.id, e
+                                            );
+                                            continue;
+                                        }
+                                    };
+                                    let tx =
+                                        self.commands_matching(&device.id, event, &sender_to_hass);
+                                    (device, tx)
+                                } else if let Some(device) = buttons.get(topic) {
+                                    // Other command results are published on the same topic
+                                    let Ok(event) = PanelEvent::from_payload(payload) else {
+                                        continue;
+                                    };
+                                    let tx =
+                                        self.commands_matching(&device.id, event, &sender_to_hass);
+                                    (device, tx)
+                                } else if let Some(device) = tele.get(topic) {
+                                    (device, self.availability_matching(device, topic, payload))
+                                } else if let Some(device) = discovery.get(topic) {
+                                    let tx = Discovery::process_command(
+                                        &self.config,
+                                        &self.store,
+                                        device,
+                                        topic,
+                                        payload,
+                                    );
+                                    (device, tx)
+                                } else {
+                                    error!("No device is configured for topic {}", topic);
+                                    continue;
+                                };
                                 info!("RX={:?}", tx);
                                 let mut futures = FuturesOrdered::new();
 
@@ -122,11 +248,29 @@ impl MqttC {
                                     futures.push_back(async {
                                         self.client
                                             .0
-                                            .publish("tx/nspanel-ds", QoS::ExactlyOnce, false, data)
+                                            .publish(
+                                                device.mqtt.rx_topic.clone(),
+                                                QoS::ExactlyOnce,
+                                                false,
+                                                data,
+                                            )
                                             .await
                                     });
                                 }
                                 while let Some(_) = futures.next().await {} //ensure commands are in order and display has time to process them.
+                                Discovery::publish_states(
+                                    &self.client.0,
+                                    &self.config,
+                                    &self.store,
+                                    device,
+                                )
+                                .await;
+                                MqttC::request_history(
+                                    &self.config,
+                                    &self.store,
+                                    device,
+                                    &sender_to_hass,
+                                );
                             }
                             _ => {
                                 // trace!(self.logger, "Uninteresting Mqtt event {:?}",e);
@@ -134,7 +278,9 @@ impl MqttC {
                         }
                     }
                     Ok(Err(e)) => {
+                        // The next poll is reconnecting
                         error!("Mqtt error event {:?}", e);
+                        backoff.wait(&shutdown).await;
                     }
                     Err(_e) => {} // Timeout
                 }
@@ -145,11 +291,79 @@ impl MqttC {
         tokio::join!(ticker_future, mqtt_handling, hass_changes_future);
     }
 
+    /// Subscribe to the panel topics, unless the broker kept them in the session, and mark the
+    /// server as `online`.
+    async fn on_connect(
+        client: AsyncClient,
+        config: Arc<Config>,
+        store: StateStore,
+        session_present: bool,
+    ) {
+        if !session_present {
+            for device in config.devices.values() {
+                let mut topics = vec![
+                    device.mqtt.tx_topic.clone(),
+                    device.tele_topic("LWT"),
+                    device.tele_topic("STATE"),
+                    device.stat_topic("RESULT"),
+                ];
+                topics.extend(Discovery::command_topics(&config, device));
+                for topic in topics {
+                    let _ = client.subscribe(&topic, QoS::AtMostOnce).await;
+                    info!("Mqtt client is register to listen on topic {}", &topic);
+                }
+            }
+        }
+        let _ = client
+            .publish(
+                &config.connectivity.mqtt.status_topic,
+                QoS::AtLeastOnce,
+                true,
+                "online",
+            )
+            .await;
+        for device in config.devices.values() {
+            Discovery::publish_configs(&client, &config, device).await;
+            Discovery::publish_states(&client, &config, &store, device).await;
+        }
+    }
+
+    /// Track the panel availability from the Tasmota `LWT` (`Online`/`Offline`) and `STATE`
+    /// (only sent by a running panel) messages. The current page is sent again when the panel
+    /// is back online, as it may have been restarted meanwhile.
+    fn availability_matching(
+        &self,
+        device: &Device,
+        topic: &str,
+        payload: &str,
+    ) -> Vec<PanelMessage> {
+        let online = !topic.ends_with("/LWT") || payload == "Online";
+        let was_online = self.store.get(&device.id).online;
+        let device_state = DeviceState {
+            online: Some(online),
+            ..Default::default()
+        };
+        self.store.update(&device.id, device_state);
+        match (online, was_online) {
+            (true, Some(false)) => {
+                info!("Device_id [{}]; Panel is online, redrawing", device.id);
+                Command::new(&self.config, &self.store, &device.id).redraw()
+            }
+            // The retained `Online` is replayed at startup and on config reload
+            (true, _) => vec![],
+            (false, _) => {
+                info!("Device_id [{}]; Panel is offline", device.id);
+                vec![]
+            }
+        }
+    }
+
     async fn send_on_event(
         publisher: AsyncClient,
         config: &Config,
+        store: &StateStore,
         shutdown: Arc<AtomicBool>,
-        receiver: Arc<Mutex<Receiver<(String, String)>>>,
+        receiver: Arc<Mutex<Receiver<(String, HassUpdate)>>>,
     ) {
         while !shutdown.load(Ordering::SeqCst) {
             let message;
@@ -163,9 +377,32 @@ impl MqttC {
                 continue;
             }
 
-            if let Some((key, value)) = message {
+            if let Some((key, update)) = message {
                 if let Some(device) = config.devices.get(key.as_str()) {
-                    let messages = Self::parse_hass_event(config.clone(), device, value);
+                    let messages = match update {
+                        HassUpdate::Entities(event) => Self::parse_hass_event(
+                            config.clone(),
+                            store,
+                            device,
+                            store.apply_entities(&device.id, event),
+                        ),
+                        HassUpdate::States(states) => Self::parse_hass_event(
+                            config.clone(),
+                            store,
+                            device,
+                            store.apply_states(&device.id, states),
+                        ),
+                        HassUpdate::ServiceFailed(service, error) => {
+                            Self::parse_service_failure(config, store, device, &service, &error)
+                        }
+                        HassUpdate::History(entity, points) => {
+                            Self::parse_history(config, store, device, entity, points)
+                        }
+                    };
+                    if !store.is_online(&device.id) {
+                        trace!("Device_id [{}]; Panel is offline, skipping", device.id);
+                        continue;
+                    }
                     info!("Sending message to mqttc channel TX: {:?}", messages);
                     for message in messages {
                         let _ = publisher
@@ -185,123 +422,287 @@ impl MqttC {
         trace!("Exiting async loop from send_on_event");
     }
 
-    fn parse_hass_event(config: Config, device: &Device, value: String) -> Vec<String> {
-        use utils::DeviceState;
-
-        let mut messages: HashMap<Card, String> = HashMap::default();
-
-        let device_state = DeviceState::get_state(&device.id);
-
-        // Getting RootEvent
-        let json = serde_yaml::from_str::<RootEvent>(&*value).unwrap();
+    fn parse_hass_event(
+        config: Config,
+        store: &StateStore,
+        device: &Device,
+        entities: BTreeMap<String, EntityState>,
+    ) -> Vec<PanelMessage> {
+        let mut messages: Vec<(Card, PanelMessage)> = vec![];
 
-        // Helper closure that takes card and vec<String> and add to messages
+        // Helper closure that takes card and vec<PanelMessage> and add to messages
         // We are using this closure to pass to the model methods, so we don't care about the
         // current page.
-        let mut insert_message = |card: Card, result: Vec<String>| {
-            let messages_to_insert = result
-                .into_iter()
-                .filter(|s| !s.is_empty())
-                .collect::<Vec<String>>();
-            messages.extend(messages_to_insert.into_iter().map(|s| (card.clone(), s)));
+        let mut insert_message = |card: Card, result: Vec<PanelMessage>| {
+            messages.extend(result.into_iter().map(|m| (card.clone(), m)));
         };
 
-        Screensaver::process_temperature_sensor(&config, &value, &device, &mut insert_message);
-        Screensaver::process_weather(&config, device, &value, &json, &mut insert_message);
-        Alarm::process_alarm_data(&config, &value, &device, &json, &mut insert_message);
+        Screensaver::process_temperature_sensor(
+            &config,
+            store,
+            device,
+            &entities,
+            &mut insert_message,
+        );
+        Screensaver::process_weather(&config, store, device, &entities, &mut insert_message);
+        Alarm::process_alarm_data(&config, store, device, &entities, &mut insert_message);
+        Thermo::process_climate_data(&config, device, &entities, &mut insert_message);
+        Grid::process_entities_data(&config, store, device, &entities, &mut insert_message);
+        Entities::process_entities_data(&config, store, device, &entities, &mut insert_message);
+        Media::process_media_data(&config, store, device, &entities, &mut insert_message);
+        Power::process_power_data(&config, store, device, &entities, &mut insert_message);
+        // The open popup is following its entity
+        if let Some((card, entity)) = store.get(&device.id).detail {
+            if entities.contains_key(&entity) {
+                let command = Command::new(&config, store, &device.id);
+                insert_message(card, command.get_detail(&entity).into_iter().collect());
+            }
+        }
+        Brightness::process_brightness_data(store, device, &entities);
 
         // Handle model only if are for the current page
-        if let Some(&ref current_page) = device_state.page.as_ref().map(|p| &p.current) {
-            messages
-                .iter()
-                .filter(|(c, _)| **c == device_state.page.clone().unwrap().current)
-                .map(|(_, s)| s.clone())
-                .collect()
-        } else {
-            messages.values().map(|s| s.clone()).collect::<Vec<_>>()
+        match store.get(&device.id).page.map(|p| p.current) {
+            Some(current_page) => messages
+                .into_iter()
+                .filter(|(c, _)| *c == current_page)
+                .map(|(_, m)| m)
+                .collect(),
+            None => messages.into_iter().map(|(_, m)| m).collect(),
+        }
+    }
+
+    fn parse_history(
+        config: &Config,
+        store: &StateStore,
+        device: &Device,
+        entity: String,
+        points: Vec<(f64, f64)>,
+    ) -> Vec<PanelMessage> {
+        let mut messages: Vec<(Card, PanelMessage)> = vec![];
+        Chart::process_history(config, store, device, entity, points, |card, result| {
+            messages.extend(result.into_iter().map(|m| (card.clone(), m)));
+        });
+        let current_page = store.get(&device.id).page.map(|p| p.current);
+        messages
+            .into_iter()
+            .filter(|(c, _)| Some(c) == current_page.as_ref())
+            .map(|(_, m)| m)
+            .collect()
+    }
+
+    /// Ask Hass for the history of the visible chart, when it is due.
+    fn request_history(
+        config: &Config,
+        store: &StateStore,
+        device: &Device,
+        sender_to_hass: &Sender<(String, HassCommand)>,
+    ) {
+        if let Some(request) = Chart::get_history_request(config, store, device) {
+            MqttC::send_to_hass(sender_to_hass, &device.id, HassCommand::History(request));
+        }
+    }
+
+    /// Feedback shown on the current page when Hass rejected a service call.
+    fn parse_service_failure(
+        config: &Config,
+        store: &StateStore,
+        device: &Device,
+        service: &ServiceCall,
+        error: &ResultError,
+    ) -> Vec<PanelMessage> {
+        info!(
+            "Device_id [{}]; Showing failure of {} ({})",
+            device.id,
+            service.name(),
+            error.message
+        );
+        let current_page = store.get(&device.id).page.map(|p| p.current);
+        match (service.domain.as_str(), current_page) {
+            // The entered code was rejected, the other failures are only logged
+            ("alarm_control_panel", Some(Card::CardAlarm)) if Alarm::is_wrong_code(error) => {
+                Alarm::get_wrong_code_update(config, store, &device.id)
+            }
+            _ => vec![],
         }
     }
 
     async fn send_periodic_message(
         publisher: AsyncClient,
         config: &Config,
+        store: &StateStore,
         shutdown: Arc<AtomicBool>,
+        sender_to_hass: Sender<(String, HassCommand)>,
     ) {
         let mut interval = interval(Duration::from_secs(10)); // Create an interval of seconds
 
         while !shutdown.load(Ordering::SeqCst) {
             trace!("Each seconds {}", 10);
             //TODO change this to send message over channel and not like how it's done now.
-            for device in config.devices.values() {
-                let tz: Tz = device
-                    .config
-                    .timezone
-                    .parse()
-                    .unwrap_or(chrono_tz::Etc::GMT);
-                let dt = Utc::now().with_timezone(&tz);
-                let time_str = format!("time~{:0>2}:{:0>2}~", dt.hour(), dt.minute());
-                let bytes = Bytes::from(time_str.into_bytes());
+            for device in config.devices.values().filter(|d| store.is_online(&d.id)) {
+                let bytes = Bytes::from(Screensaver::get_time(device));
                 let _ = publisher
                     .publish(device.mqtt.rx_topic.clone(), QoS::ExactlyOnce, false, bytes)
                     .await;
+                // A screensaver brightness schedule boundary was crossed
+                if let Some(dim_mode) = Brightness::get_dim_update(store, device) {
+                    let _ = publisher
+                        .publish(
+                            device.mqtt.rx_topic.clone(),
+                            QoS::ExactlyOnce,
+                            false,
+                            dim_mode,
+                        )
+                        .await;
+                    Discovery::publish_states(&publisher, config, store, device).await;
+                }
+                MqttC::request_history(config, store, device, &sender_to_hass);
             }
             interval.tick().await;
         }
         trace!("Exiting interval loop from send_periodic_message");
     }
-    fn commands_matching(&mut self, device_id: &str, payload: &str) -> Vec<Bytes> {
+    fn commands_matching(
+        &mut self,
+        device_id: &str,
+        event: PanelEvent,
+        sender_to_hass: &Sender<(String, HassCommand)>,
+    ) -> Vec<PanelMessage> {
         let config = &self.config.clone();
-        let command = Command::new(config, device_id);
-        let result = serde_json::from_str(payload)
-            .map(move |data: Value| {
-                let d = data.as_object();
-                if let Some(o) = d {
-                    for (key, value) in o {
-                        match key.as_str() {
-                            "CustomRecv" => {
-                                let tokens = value.to_string();
-                                info!("Device_id [{}] Tokens {:?}", device_id, tokens);
-                                if tokens.starts_with(r#""event,startup,"#) {
-                                    return command.execute(Page::Startup);
-                                } else if tokens.starts_with(r#""event,sleepReached,"#) {
-                                    return command.execute(Page::Screensaver);
-                                } else if tokens
-                                    .starts_with(r#""event,buttonPress2,screensaver,bExit,"#)
-                                {
-                                    // Get previous page and display it.
-                                    return command.execute(Page::ExistScreensaver);
-                                } else if let Some(captured) =
-                                    regex::Regex::new(r#"event,buttonPress2,(.*?),(bNext|bPrev)"#)
-                                        .expect("Failed to parse the regex for bNext action")
-                                        .captures(&tokens)
-                                {
-                                    if let Some(group) = captured.get(1) {
-                                        // this is the group for current page
-                                        if let Some(card) = config.get_adjacent_card(
-                                            device_id,
-                                            group.as_str(),
-                                            captured.get(2).unwrap().as_str().eq("bNext"),
-                                        ) {
-                                            return command
-                                                .execute(Page::from(card.type_.as_str()));
-                                        }
-                                    }
-                                }
-                            }
-                            _ => {}
-                        }
+        let command = Command::new(config, &self.store, device_id);
+        info!("Device_id [{}] Event {}", device_id, event);
+        // Every event is also available to Hass automations
+        let page = self
+            .store
+            .get(device_id)
+            .page
+            .map_or("", |p| p.current.as_str());
+        let fire_event = FireEvent::panel_event(device_id, page, &event);
+        MqttC::send_to_hass(
+            sender_to_hass,
+            device_id,
+            HassCommand::FireEvent(fire_event),
+        );
+        match event {
+            PanelEvent::Startup { .. } => command.execute(Page::Startup),
+            PanelEvent::SleepReached { .. } => command.execute(Page::Screensaver),
+            PanelEvent::ButtonPress2 { entity, action, .. }
+                if entity == "screensaver" && action == "bExit" =>
+            {
+                // Get previous page and display it.
+                command.execute(Page::ExistScreensaver)
+            }
+            PanelEvent::ButtonPress2 {
+                entity: group,
+                action,
+                ..
+            } if action == "bNext" || action == "bPrev" => {
+                let forward = action == "bNext";
+                // Cards with more entities are first paginated
+                if let Some(result) = command.sub_page(forward) {
+                    return result;
+                }
+                // this is the group for current page, `cardHome` is
+                // reported by the panel as `cardGrid` so prefer the stored one
+                let page = self.store.get(device_id).page.unwrap_or_default();
+                let current = Some(page.current)
+                    .filter(|c| *c != Card::Screensaver)
+                    .map_or(group, |c| c.as_str().to_string());
+                // The keypad is standing in for the locked card
+                let current = match current.as_str() {
+                    "cardUnlock" => self.store.get(device_id).unlock_target.unwrap_or(current),
+                    _ => current,
+                };
+                // Cards of the same type are told apart by the stored index
+                config
+                    .get_card_index(device_id, page.index, &current)
+                    .and_then(|index| config.get_adjacent_card(device_id, index, forward))
+                    .map(|index| command.open_index(index))
+                    .unwrap_or_default()
+            }
+            PanelEvent::ButtonPress2 {
+                entity,
+                action,
+                value,
+            } if action == UNLOCK_ACTION => {
+                let card = entity.trim_start_matches("cardUnlock-");
+                command.unlock(card, value.as_deref().unwrap_or_default())
+            }
+            PanelEvent::PageOpenDetail { popup, entity } if popup == "popupThermo" => {
+                command.thermo_detail(&entity)
+            }
+            PanelEvent::PageOpenDetail { popup, entity }
+                if matches!(popup.as_str(), "popupLight" | "popupShutter" | "popupFan") =>
+            {
+                command.open_detail(&entity)
+            }
+            PanelEvent::PageOpenDetail { popup, entity } if popup == "popupInSel" => {
+                command.select_detail(&entity)
+            }
+            PanelEvent::ButtonPress2 {
+                entity,
+                action,
+                value,
+            } => {
+                let value = value.as_deref().unwrap_or_default();
+                let service = match entity.split('.').next().unwrap_or_default() {
+                    "climate" => {
+                        Thermo::service_call(&self.store, device_id, &entity, &action, value)
+                    }
+                    "alarm_control_panel" => Alarm::service_call(&entity, &action, value),
+                    "media_player" => {
+                        Media::service_call(&self.store, device_id, &entity, &action, value)
+                            .or_else(|| {
+                                let value = Some(value).filter(|v| !v.is_empty());
+                                Grid::service_call(&entity, &action, value)
+                            })
                     }
-                    vec![]
-                } else {
-                    vec![]
+                    "cover" => Cover::service_call(&entity, &action, value).or_else(|| {
+                        let value = Some(value).filter(|v| !v.is_empty());
+                        Grid::service_call(&entity, &action, value)
+                    }),
+                    "input_select" | "select" => {
+                        Select::service_call(&self.store, device_id, &entity, &action, value)
+                    }
+                    "fan" => Fan::service_call(&self.store, device_id, &entity, &action, value)
+                        .or_else(|| {
+                            let value = Some(value).filter(|v| !v.is_empty());
+                            Grid::service_call(&entity, &action, value)
+                        }),
+                    "light" => Light::service_call(&self.store, device_id, &entity, &action, value)
+                        .or_else(|| {
+                            let value = Some(value).filter(|v| !v.is_empty());
+                            Grid::service_call(&entity, &action, value)
+                        }),
+                    _ => {
+                        let value = Some(value).filter(|v| !v.is_empty());
+                        Grid::service_call(&entity, &action, value)
+                            .or_else(|| Entities::service_call(&entity, &action, value))
+                    }
+                };
+                if let Some(service) = service {
+                    MqttC::send_to_hass(
+                        sender_to_hass,
+                        device_id,
+                        HassCommand::CallService(service),
+                    );
                 }
-            })
-            .map_err(|e| {
-                error!(
-                    "Device_id [{}]; Unable to parse payload  error event {:?}",
-                    device_id, e
-                );
-            });
-        result.unwrap_or_else(|_| vec![])
+                vec![]
+            }
+            PanelEvent::PageOpenDetail { .. } | PanelEvent::Button { .. } => vec![],
+        }
+    }
+
+    /// Queue a command requested by the panel, it is sent by the Hass connection.
+    fn send_to_hass(
+        sender_to_hass: &Sender<(String, HassCommand)>,
+        device_id: &str,
+        command: HassCommand,
+    ) {
+        if let Err(e) = sender_to_hass.try_send((device_id.to_string(), command)) {
+            error!(
+                "Device_id [{}]; Unable to send command to Hass {:?}",
+                device_id, e
+            );
+        }
     }
 }
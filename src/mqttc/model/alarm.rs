@@ -1,8 +1,15 @@
 use crate::cards::Card;
 use crate::config::schema::{Config, Device, Entity};
-use crate::homeassitant::events::{Alarm as AlarmD, AlarmEvent, RootEvent};
-use crate::utils::{AlarmState, DeviceState};
+use crate::homeassitant::commands::ServiceCall;
+use crate::homeassitant::entities::EntityState;
+use crate::homeassitant::events::ResultError;
+use crate::protocol::{PanelMessage, NAVIGATION};
+use crate::state::{AlarmState, DeviceState, StateStore};
 use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Number of arm/disarm buttons available on the alarm page.
+const ALARM_BUTTONS: usize = 4;
 
 pub struct Alarm {}
 
@@ -11,179 +18,268 @@ impl Alarm {
     /// For more details look on `Alarm::get_alarm()` function.
     pub fn process_alarm_data<F>(
         config: &Config,
-        value: &str,
+        store: &StateStore,
         device: &Device,
-        json: &RootEvent,
+        entities: &BTreeMap<String, EntityState>,
         mut insert_message: F,
     ) where
-        F: FnMut(Card, Vec<String>),
+        F: FnMut(Card, Vec<PanelMessage>),
     {
-        // Alarm
-        if let Some(alarm) = device.get_entity_by_name(&"alarm") {
-            if let Some(v) = json.event.entities.get(&*alarm.entity) {
-                // Removing cases when weather is disabled/unavailable. Unable to map to existing event struct
-                if !v.to_string().contains(r#""a":{"restored":true"#)
-                    && !v.to_string().contains(r#"s":"unavailable"#)
-                {
-                    insert_message(
-                        Card::CardAlarm,
-                        Alarm::get_alarm(config, value, device, alarm, v),
-                    );
-                }
+        if let Some(alarm) = Alarm::get_alarm_entity(config, device) {
+            if let Some(state) = entities.get(&alarm.entity).filter(|s| s.is_available()) {
+                insert_message(
+                    Card::CardAlarm,
+                    Alarm::get_alarm(config, store, device, alarm, state),
+                );
             }
         }
     }
 
+    /// The alarm entity is the one named `alarm` or the first entity of `cardAlarm`.
+    fn get_alarm_entity(config: &Config, device: &Device) -> Option<Entity> {
+        device.get_entity_by_name("alarm").or_else(|| {
+            config
+                .get_card_by_name(&device.id, Card::CardAlarm.as_str())
+                .and_then(|card| card.entities.into_iter().next())
+        })
+    }
+
     fn get_alarm(
         config: &Config,
-        value: &str,
+        store: &StateStore,
         device: &Device,
         alarm: Entity,
-        v: &Value,
-    ) -> Vec<String> {
-        let alarm_d;
-        if value.contains(format!(r#"{}":{{"s"#, alarm.entity).as_str()) {
-            let a: AlarmEvent =
-                serde_json::from_value(v.clone()).expect("Failed to convert to AlarmEvent struct");
-            alarm_d = a;
-        } else {
-            let a: AlarmD =
-                serde_json::from_value(v.clone()).expect("Failed to convert to Weather struct");
-            alarm_d = a.event;
+        entity: &EntityState,
+    ) -> Vec<PanelMessage> {
+        let mut supported_modes: Vec<&str> = vec![];
+        let bits = entity
+            .attributes
+            .get("supported_features")
+            .and_then(Value::as_u64)
+            .unwrap_or_default();
+        if bits & 0b000001 != 0 {
+            supported_modes.push("Arm Home~arm_home");
+        }
+        if bits & 0b000010 != 0 {
+            supported_modes.push("Arm Away~arm_away");
+        }
+        if bits & 0b000100 != 0 {
+            supported_modes.push("Arm Night~arm_night");
         }
-        let mut device_state = DeviceState::default();
-        let mut alarm_state = AlarmState {
-            state: "".to_string(),
-            supported_mode: "".to_string(),
-            code_arm_required: None,
-            entity: "".to_string(),
-            icon: ("".to_string(), 0),
+        if bits & 0b100000 != 0 {
+            supported_modes.push("Arm Vacation~arm_vacation");
+        }
+        let alarm_state = AlarmState {
+            state: entity.state.clone(),
+            supported_mode: supported_modes.join("~"),
+            code_arm_required: Some(
+                entity
+                    .attributes
+                    .get("code_arm_required")
+                    .and_then(Value::as_bool)
+                    .unwrap_or_default(),
+            ),
+            entity: alarm.entity,
+            icon: Alarm::get_state_icon(config, &entity.state).0,
         };
-        if let Some(data) = alarm_d.data {
-            let mut supported_modes: Vec<&str> = vec![];
-            let bits = data.supported_features.unwrap_or_default();
-            if bits & 0b000001 != 0 {
-                supported_modes.push("Arm Home~arm_home");
-            }
-            if bits & 0b000010 != 0 {
-                supported_modes.push("Arm Away~arm_away");
-            }
-            if bits & 0b000100 != 0 {
-                supported_modes.push("Arm Night~arm_night");
-            }
-            if bits & 0b100000 != 0 {
-                supported_modes.push("Arm Vacation~arm_vacation");
-            }
-            alarm_state.supported_mode = supported_modes.join("~");
-            alarm_state.code_arm_required = Some(data.code_arm_required.unwrap_or_default());
-            alarm_state.entity = alarm.entity;
+        let device_state = DeviceState {
+            alarm: Some(alarm_state),
+            ..Default::default()
+        };
+        store.update(&device.id, device_state);
+        let device_state = store.get(&device.id);
+        if let Some(alarm) = device_state.alarm {
+            return vec![Alarm::get_alarm_update(config, &device.id, &alarm)];
         }
-        alarm_state.state = alarm_d.state.clone().unwrap_or_default();
-
-        let mut icon: (String, u32) = ("".to_string(), 0);
+        vec![]
+    }
 
-        let _device_state = DeviceState::get_state(&device.id);
-        let mut code_arm_required = true;
-        if let Some(alarm) = _device_state.alarm {
-            code_arm_required = alarm.code_arm_required.unwrap_or(true); // show by default numkey
+    /// Icon, color and flashing flag for the provided alarm state.
+    pub fn get_state_icon(config: &Config, state: &str) -> ((String, u32), bool) {
+        let icon = |name: &str| config.icons.get(name).map_or('\0', |&c| c).to_string();
+        match state {
+            "disarmed" => ((icon("shield-off"), 3334), false),
+            "armed_home" => ((icon("shield-home"), 55907), false),
+            "armed_away" => ((icon("shield-lock"), 55907), false),
+            "armed_night" => ((icon("weather-night"), 55907), false),
+            "armed_vacation" => ((icon("shield-airplane"), 55907), false),
+            "pending" | "arming" => ((icon("shield"), 62848), true),
+            "triggered" => ((icon("bell-ring"), 55907), true),
+            _ => (("".to_string(), 0), false),
         }
+    }
 
-        let mut numkey = true;
-        let mut falshing = false;
-        if let Some(ref state) = alarm_d.state {
-            match state.as_str() {
-                "disarmed" => {
-                    icon = (
-                        config
-                            .icons
-                            .get("shield-off")
-                            .map_or('\0', |&c| c)
-                            .to_string(),
-                        3334,
-                    );
-                    if !code_arm_required {
-                        numkey = false;
-                    }
-                }
-                "armed_home" => {
-                    icon = (
-                        config
-                            .icons
-                            .get("shield-home")
-                            .map_or('\0', |&c| c)
-                            .to_string(),
-                        55907,
-                    );
-                }
-                "armed_away" => {
-                    icon = (
-                        config
-                            .icons
-                            .get("shield-lock")
-                            .map_or('\0', |&c| c)
-                            .to_string(),
-                        55907,
-                    );
-                }
-                "armed_night" => {
-                    icon = (
-                        config
-                            .icons
-                            .get("weather-night")
-                            .map_or('\0', |&c| c)
-                            .to_string(),
-                        55907,
-                    );
-                }
-                "armed_vacation" => {
-                    icon = (
-                        config
-                            .icons
-                            .get("shield-airplane")
-                            .map_or('\0', |&c| c)
-                            .to_string(),
-                        55907,
-                    );
-                }
-                "pending" | "arming" => {
-                    icon = (
-                        config.icons.get("shield").map_or('\0', |&c| c).to_string(),
-                        62848,
-                    );
-                    falshing = true;
-                }
-                "triggered" => {
-                    icon = (
-                        config
-                            .icons
-                            .get("bell-ring")
-                            .map_or('\0', |&c| c)
-                            .to_string(),
-                        55907,
-                    );
-                    falshing = true;
+    /// Build the alarm page update.
+    /// When disarmed the supported arm modes are shown, otherwise only the disarm button.
+    /// * Message format, `{name}~{action}` is repeated for each of the 4 buttons
+    /// ```
+    /// entityUpd~{title}~1|1~{entity}~{name}~{action}~...~{icon}~{color}~{numkey}~{flashing}~
+    /// ```
+    pub fn get_alarm_update(config: &Config, device_id: &str, alarm: &AlarmState) -> PanelMessage {
+        let (_, flashing) = Alarm::get_state_icon(config, &alarm.state);
+        Alarm::format_update(
+            config,
+            device_id,
+            alarm,
+            &alarm.icon,
+            Alarm::has_numkey(alarm),
+            flashing,
+        )
+    }
+
+    /// Build the alarm page update shown when Hass rejected the entered code.
+    /// The regular update is sent back on the next alarm state change or page visit.
+    pub fn get_wrong_code_update(
+        config: &Config,
+        store: &StateStore,
+        device_id: &str,
+    ) -> Vec<PanelMessage> {
+        store
+            .get(device_id)
+            .alarm
+            .map(|alarm| {
+                let icon = (
+                    config
+                        .icons
+                        .get("shield-alert")
+                        .map_or('\0', |&c| c)
+                        .to_string(),
+                    63488, // red
+                );
+                vec![Alarm::format_update(
+                    config, device_id, &alarm, &icon, true, true,
+                )]
+            })
+            .unwrap_or_default()
+    }
+
+    /// Translate an alarm keypad button press into a Hass `alarm_control_panel` service call.
+    /// * `arm_home,1234` -> `alarm_control_panel.alarm_arm_home` with `code: 1234`
+    /// * `disarm,1234` -> `alarm_control_panel.alarm_disarm` with `code: 1234`
+    pub fn service_call(entity: &str, action: &str, code: &str) -> Option<ServiceCall> {
+        match action {
+            "disarm" | "arm_home" | "arm_away" | "arm_night" | "arm_vacation"
+            | "arm_custom_bypass" => {
+                let service =
+                    ServiceCall::new("alarm_control_panel", &format!("alarm_{}", action), entity);
+                if code.is_empty() {
+                    Some(service)
+                } else {
+                    Some(service.with("code", code))
                 }
-                _ => {}
             }
+            _ => None,
         }
-        alarm_state.icon = icon;
-        device_state.alarm = Some(alarm_state);
+    }
 
-        DeviceState::read_process_overwrite(&device.id, device_state);
-        let device_state = DeviceState::get_state(&device.id);
-        if let Some(alarm) = device_state.alarm {
-            let r_update = format!(
-                "entityUpd~{}~1|1~{}~{}~{}~{}~{}~",
-                alarm.entity,
-                alarm.supported_mode,
-                alarm.icon.0,
-                alarm.icon.1,
-                if !numkey { "disable" } else { "enable" },
-                if falshing { "enable" } else { "disable" },
-            )
-            .into();
-            return vec![r_update];
+    /// Whether Hass rejected the service call because of the entered code. Hass is reporting it
+    /// with the `invalid_code` translation key, older versions only with the message.
+    pub fn is_wrong_code(error: &ResultError) -> bool {
+        if error.translation_key.as_deref() == Some("invalid_code") {
+            return true;
         }
-        vec![]
+        let message = error.message.to_lowercase();
+        message.contains("code")
+            && ["invalid", "incorrect", "wrong"]
+                .iter()
+                .any(|word| message.contains(word))
+    }
+
+    /// The numkey is hidden only when disarmed and no code is required to arm.
+    fn has_numkey(alarm: &AlarmState) -> bool {
+        let code_arm_required = alarm.code_arm_required.unwrap_or(true); // show by default numkey
+        alarm.state != "disarmed" || code_arm_required
+    }
+
+    fn format_update(
+        config: &Config,
+        device_id: &str,
+        alarm: &AlarmState,
+        icon: &(String, u32),
+        numkey: bool,
+        flashing: bool,
+    ) -> PanelMessage {
+        let title = config
+            .get_card_by_name(device_id, Card::CardAlarm.as_str())
+            .and_then(|card| card.title)
+            .unwrap_or_default();
+        let mut buttons: Vec<String> = if alarm.state == "disarmed" {
+            alarm
+                .supported_mode
+                .split('~')
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect()
+        } else {
+            vec!["Disarm".to_string(), "disarm".to_string()]
+        };
+        // Unused buttons are sent empty
+        buttons.resize(ALARM_BUTTONS * 2, String::default());
+
+        let items = [
+            vec![alarm.entity.clone()],
+            buttons,
+            vec![
+                icon.0.clone(),
+                icon.1.to_string(),
+                if !numkey { "disable" } else { "enable" }.to_string(),
+                if flashing { "enable" } else { "disable" }.to_string(),
+                String::default(),
+            ],
+        ]
+        .concat();
+        PanelMessage::EntityUpd {
+            heading: title,
+            navigation: NAVIGATION.to_string(),
+            items,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn service_call_with_code() {
+        let service = Alarm::service_call("alarm_control_panel.home", "arm_away", "1234").unwrap();
+        assert_eq!(service.domain, "alarm_control_panel");
+        assert_eq!(service.service, "alarm_arm_away");
+        assert_eq!(service.service_data["code"], json!("1234"));
+        assert_eq!(
+            service.service_data["entity_id"],
+            json!("alarm_control_panel.home")
+        );
+
+        // The code is optional to arm
+        let service = Alarm::service_call("alarm_control_panel.home", "arm_home", "").unwrap();
+        assert_eq!(service.service, "alarm_arm_home");
+        assert!(!service.service_data.contains_key("code"));
+
+        assert!(Alarm::service_call("alarm_control_panel.home", "trigger", "1234").is_none());
+    }
+
+    #[test]
+    fn wrong_code_is_recognized() {
+        let error = |message: &str, translation_key: Option<&str>| ResultError {
+            code: "home_assistant_error".to_string(),
+            message: message.to_string(),
+            translation_key: translation_key.map(str::to_string),
+        };
+        assert!(Alarm::is_wrong_code(&error(
+            "Invalid alarm code provided",
+            Some("invalid_code")
+        )));
+        assert!(Alarm::is_wrong_code(&error(
+            "Invalid alarm code provided",
+            None
+        )));
+        assert!(Alarm::is_wrong_code(&error("Incorrect code", None)));
+        assert!(!Alarm::is_wrong_code(&error(
+            "Entity alarm_control_panel.home is unavailable",
+            None
+        )));
+        assert!(!Alarm::is_wrong_code(&error("Connection lost", None)));
     }
 }
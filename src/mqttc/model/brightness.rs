@@ -0,0 +1,198 @@
+use crate::config::schema::Device;
+use crate::homeassitant::entities::EntityState;
+use crate::protocol::PanelMessage;
+use crate::state::{DeviceState, StateStore};
+use chrono::{NaiveTime, Utc};
+use log::error;
+use std::collections::BTreeMap;
+
+/// Screensaver brightness used when no schedule is configured.
+const DEFAULT_BRIGHTNESS: u16 = 10;
+/// Brightness while the panel is in use.
+const ACTIVE_BRIGHTNESS: u16 = 100;
+const BACKGROUND_COLOR: u32 = 6371;
+
+/// The screensaver brightness, driven by `config.screensaver_brightness`.
+/// Each schedule entry is active from its `time` until the next entry, the last entry of the
+/// day is wrapping around midnight.
+pub struct Brightness {}
+
+impl Brightness {
+    /// Store the value of the `input_number` entities used by the brightness schedule.
+    /// The change is sent to the panel by the next `Brightness::get_dim_update()` call.
+    pub fn process_brightness_data(
+        store: &StateStore,
+        device: &Device,
+        entities: &BTreeMap<String, EntityState>,
+    ) {
+        let brightness: BTreeMap<String, u16> = device
+            .config
+            .screensaver_brightness
+            .iter()
+            .filter_map(|schedule| schedule.entity.as_ref())
+            .filter_map(|entity| {
+                let value = entities.get(entity)?.state.parse::<f64>().ok()?;
+                Some((entity.clone(), value.round().clamp(0.0, 100.0) as u16))
+            })
+            .collect();
+        if brightness.is_empty() {
+            return;
+        }
+        let device_state = DeviceState {
+            brightness,
+            ..Default::default()
+        };
+        store.update(&device.id, device_state);
+    }
+
+    /// Build the `dimmode` message for the current schedule and remember the sent brightness.
+    /// A brightness set by `Brightness::set_dim()` is kept until the next schedule boundary.
+    /// * Message format
+    /// ```
+    /// dimmode~{screensaver brightness}~{active brightness}~{background color}
+    /// ```
+    pub fn get_dim_mode(store: &StateStore, device: &Device) -> PanelMessage {
+        let device_state = store.get(&device.id);
+        let scheduled = Brightness::get_scheduled(device, &device_state);
+        let dim = match device_state.dim {
+            Some(dim) if device_state.dim_schedule == Some(scheduled) => dim,
+            _ => scheduled,
+        };
+        Brightness::set_dim(store, device, dim)
+    }
+
+    /// Same as `Brightness::get_dim_mode()`, but only when a schedule boundary was crossed since
+    /// the last time.
+    pub fn get_dim_update(store: &StateStore, device: &Device) -> Option<PanelMessage> {
+        let device_state = store.get(&device.id);
+        if device_state.dim_schedule == Some(Brightness::get_scheduled(device, &device_state)) {
+            return None;
+        }
+        Some(Brightness::get_dim_mode(store, device))
+    }
+
+    /// Override the screensaver brightness until the next schedule boundary.
+    pub fn set_dim(store: &StateStore, device: &Device, dim: u16) -> PanelMessage {
+        let scheduled = Brightness::get_scheduled(device, &store.get(&device.id));
+        let device_state = DeviceState {
+            dim: Some(dim),
+            dim_schedule: Some(scheduled),
+            ..Default::default()
+        };
+        store.update(&device.id, device_state);
+        PanelMessage::DimMode {
+            dim,
+            active: ACTIVE_BRIGHTNESS,
+            background: BACKGROUND_COLOR,
+        }
+    }
+
+    /// Brightness of the schedule entry active now, in the device timezone.
+    fn get_scheduled(device: &Device, device_state: &DeviceState) -> u16 {
+        let now = Utc::now().with_timezone(&device.timezone()).time();
+        Brightness::scheduled_at(device, device_state, now)
+    }
+
+    /// Brightness of the schedule entry active at `now`.
+    fn scheduled_at(device: &Device, device_state: &DeviceState, now: NaiveTime) -> u16 {
+        let mut schedule: Vec<(NaiveTime, u16)> = device
+            .config
+            .screensaver_brightness
+            .iter()
+            .filter_map(|schedule| {
+                let Ok(time) = NaiveTime::parse_from_str(&schedule.time, "%H:%M:%S") else {
+                    error!(
+                        "Device_id [{}]; Invalid screensaver_brightness time {}",
+                        device.id, schedule.time
+                    );
+                    return None;
+                };
+                let value = schedule
+                    .entity
+                    .as_ref()
+                    .and_then(|entity| device_state.brightness.get(entity))
+                    .copied()
+                    .unwrap_or(schedule.value);
+                Some((time, value))
+            })
+            .collect();
+        schedule.sort_by_key(|(time, _)| *time);
+
+        schedule
+            .iter()
+            .rev()
+            .find(|(time, _)| *time <= now)
+            // Before the first entry of the day the last one is still active
+            .or_else(|| schedule.last())
+            .map_or(DEFAULT_BRIGHTNESS, |(_, value)| *value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::schema::Config;
+
+    const DEVICES: &str = r#"
+        panel:
+          module: nspanel
+          id: panel
+          mqtt: { rx_topic: cmnd/panel/CustomSend, tx_topic: tele/panel/RESULT }
+          model: EU
+          config:
+            timeout_to_screensaver: 20
+            locale: en_US
+            timezone: Europe/Bucharest
+            screensaver_brightness:
+              - { time: "07:00:00", value: 60 }
+              - { time: "22:00:00", value: 5, entity: input_number.night_brightness }
+              - { time: "12:00:00", value: 80 }
+              - { time: "25:00:00", value: 1 }
+          cards: []
+    "#;
+
+    fn at(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn schedule_wraps_around_midnight() {
+        let config = Config::from_yaml(DEVICES);
+        let device = &config.devices["panel"];
+        let state = DeviceState::default();
+        let scheduled = |time: NaiveTime| Brightness::scheduled_at(device, &state, time);
+
+        assert_eq!(scheduled(at(7, 0)), 60);
+        assert_eq!(scheduled(at(11, 59)), 60);
+        assert_eq!(scheduled(at(12, 0)), 80);
+        assert_eq!(scheduled(at(21, 59)), 80);
+        assert_eq!(scheduled(at(22, 0)), 5);
+        // The last entry of the day is active until the first one of the next day
+        assert_eq!(scheduled(at(23, 59)), 5);
+        assert_eq!(scheduled(at(0, 0)), 5);
+        assert_eq!(scheduled(at(6, 59)), 5);
+    }
+
+    #[test]
+    fn input_number_overrides_value() {
+        let config = Config::from_yaml(DEVICES);
+        let device = &config.devices["panel"];
+        let state = DeviceState {
+            brightness: BTreeMap::from([("input_number.night_brightness".to_string(), 20)]),
+            ..Default::default()
+        };
+        assert_eq!(Brightness::scheduled_at(device, &state, at(23, 0)), 20);
+        assert_eq!(Brightness::scheduled_at(device, &state, at(8, 0)), 60);
+    }
+
+    #[test]
+    fn default_without_schedule() {
+        let mut config = Config::from_yaml(DEVICES);
+        let device = config.devices.get_mut("panel").unwrap();
+        device.config.screensaver_brightness.clear();
+        assert_eq!(
+            Brightness::scheduled_at(device, &DeviceState::default(), at(12, 0)),
+            DEFAULT_BRIGHTNESS
+        );
+    }
+}
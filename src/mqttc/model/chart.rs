@@ -0,0 +1,260 @@
+use crate::cards::Card;
+use crate::config::schema::{Config, Device};
+use crate::homeassitant::commands::HistoryRequest;
+use crate::mqttc::model::grid::COLOR_ON;
+use crate::protocol::{PanelMessage, NAVIGATION};
+use crate::state::{DeviceState, StateStore};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use std::collections::BTreeMap;
+
+/// History period when the card has no `hours`.
+const DEFAULT_HOURS: u32 = 24;
+/// Refresh interval when the card has no `refresh`, in seconds.
+const DEFAULT_REFRESH: u64 = 300;
+/// Number of y axis intervals.
+const Y_TICKS: u32 = 4;
+/// Number of x axis labels.
+const X_LABELS: usize = 4;
+
+/// The bar chart (`cardChart`) and line chart (`cardLChart`) pages, displaying the history of
+/// the first entity of the card. The history is requested from Hass when the page is shown and
+/// every `refresh` seconds while it stays visible.
+pub struct Chart {}
+
+impl Chart {
+    pub fn is_chart(card: &Card) -> bool {
+        matches!(card, Card::CardChart | Card::CardLChart)
+    }
+
+    /// Number of values displayed by the chart, the history is averaged to fit it.
+    fn bars(card: &Card) -> usize {
+        match card {
+            Card::CardLChart => 48,
+            _ => 24,
+        }
+    }
+
+    /// Store the history received from Hass and pass back the charts displaying it into the
+    /// insert_message function. For more details look on `Chart::get_chart()` function.
+    pub fn process_history<F>(
+        config: &Config,
+        store: &StateStore,
+        device: &Device,
+        entity: String,
+        points: Vec<(f64, f64)>,
+        mut insert_message: F,
+    ) where
+        F: FnMut(Card, Vec<PanelMessage>),
+    {
+        let device_state = DeviceState {
+            history: BTreeMap::from([(entity.clone(), points)]),
+            ..Default::default()
+        };
+        store.update(&device.id, device_state);
+
+        let device_state = store.get(&device.id);
+        for card in [Card::CardChart, Card::CardLChart] {
+            if Chart::get_chart_entity(config, &device.id, &card).as_ref() != Some(&entity) {
+                continue;
+            }
+            if let Some(update) = Chart::get_chart(config, device, &device_state, &card) {
+                insert_message(card, vec![update]);
+            }
+        }
+    }
+
+    /// The history request for the visible chart, when it was never requested or the
+    /// `refresh` interval has passed.
+    pub fn get_history_request(
+        config: &Config,
+        store: &StateStore,
+        device: &Device,
+    ) -> Option<HistoryRequest> {
+        let device_state = store.get(&device.id);
+        let card = device_state.page?.current;
+        if !Chart::is_chart(&card) {
+            return None;
+        }
+        let config_card = config.get_card_by_name(&device.id, card.as_str())?;
+        let entity = config_card.entities.first()?.entity.clone();
+        let now = Utc::now();
+        let refresh = config_card.refresh.unwrap_or(DEFAULT_REFRESH) as i64;
+        if let Some(requested) = device_state.history_requested.get(&entity) {
+            if now.timestamp() - requested < refresh {
+                return None;
+            }
+        }
+
+        let device_state = DeviceState {
+            history_requested: BTreeMap::from([(entity.clone(), now.timestamp())]),
+            ..Default::default()
+        };
+        store.update(&device.id, device_state);
+        let hours = config_card.hours.unwrap_or(DEFAULT_HOURS);
+        Some(HistoryRequest {
+            entity,
+            start: now - Duration::hours(hours as i64),
+            end: now,
+        })
+    }
+
+    /// The chart entity is the first entity of the card.
+    fn get_chart_entity(config: &Config, device_id: &str, card: &Card) -> Option<String> {
+        config
+            .get_card_by_name(device_id, card.as_str())
+            .and_then(|card| card.entities.into_iter().next())
+            .map(|entity| entity.entity)
+    }
+
+    /// Build the chart page update from the stored history.
+    /// * Message format, a value can have an x axis label `{value}^{label}`
+    /// ```
+    /// entityUpd~{title}~1|1~{color}~{y axis label}~{tick}:{tick}...~{value}:{value}...
+    /// ```
+    pub fn get_chart(
+        config: &Config,
+        device: &Device,
+        device_state: &DeviceState,
+        card: &Card,
+    ) -> Option<PanelMessage> {
+        let config_card = config.get_card_by_name(&device.id, card.as_str())?;
+        let entity = config_card.entities.first()?;
+        let points = device_state.history.get(&entity.entity)?;
+        let bars = Chart::bars(card);
+        let end = device_state
+            .history_requested
+            .get(&entity.entity)
+            .copied()
+            .unwrap_or_else(|| Utc::now().timestamp()) as f64;
+        let period = config_card.hours.unwrap_or(DEFAULT_HOURS) as f64 * 3600.0;
+        let values = Chart::downsample(points, end - period, end, bars);
+
+        let min = values.iter().copied().fold(0.0, f64::min);
+        let max = values.iter().copied().fold(0.0, f64::max);
+        let step = Chart::tick_step((max - min) / Y_TICKS as f64);
+        let first_tick = (min / step).floor() as i64;
+        let last_tick = (max / step).ceil().max(1.0) as i64;
+        let ticks: Vec<String> = (first_tick..=last_tick)
+            .map(|tick| (tick as f64 * step).to_string())
+            .collect();
+
+        let timezone = device.timezone();
+        let width = period / bars as f64;
+        let values: Vec<String> = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let value = value.round();
+                if i % (bars / X_LABELS) != 0 {
+                    return value.to_string();
+                }
+                let time = Utc
+                    .timestamp_opt((end - period + i as f64 * width) as i64, 0)
+                    .single()
+                    .map(|t: DateTime<Utc>| t.with_timezone(&timezone).format("%H:%M"))
+                    .map(|t| t.to_string())
+                    .unwrap_or_default();
+                format!("{}^{}", value, time)
+            })
+            .collect();
+
+        let y_label = device_state
+            .entities
+            .get(&entity.entity)
+            .and_then(|s| s.attribute_str("unit_of_measurement"))
+            .map(str::to_string)
+            .or_else(|| entity.name.clone())
+            .unwrap_or_default();
+        Some(PanelMessage::EntityUpd {
+            heading: config_card.title.unwrap_or_default(),
+            navigation: NAVIGATION.to_string(),
+            items: vec![
+                entity.color.unwrap_or(COLOR_ON).to_string(),
+                y_label,
+                ticks.join(":"),
+                values.join(":"),
+            ],
+        })
+    }
+
+    /// Average the history in `bars` intervals between `start` and `end`. An interval without
+    /// any change is keeping the last known value.
+    fn downsample(points: &[(f64, f64)], start: f64, end: f64, bars: usize) -> Vec<f64> {
+        let width = (end - start) / bars as f64;
+        let mut last = points
+            .iter()
+            .take_while(|(time, _)| *time < start)
+            .last()
+            .map_or(0.0, |(_, value)| *value);
+        (0..bars)
+            .map(|i| {
+                let from = start + i as f64 * width;
+                let samples: Vec<f64> = points
+                    .iter()
+                    .filter(|(time, _)| *time >= from && *time < from + width)
+                    .map(|(_, value)| *value)
+                    .collect();
+                if let Some(value) = samples.last() {
+                    let average = samples.iter().sum::<f64>() / samples.len() as f64;
+                    last = *value;
+                    average
+                } else {
+                    last
+                }
+            })
+            .collect()
+    }
+
+    /// Round the y axis interval up to 1, 2 or 5 times a power of 10.
+    fn tick_step(raw: f64) -> f64 {
+        if raw <= 0.0 {
+            return 1.0;
+        }
+        let magnitude = 10f64.powf(raw.log10().floor());
+        let step = [1.0, 2.0, 5.0, 10.0]
+            .into_iter()
+            .map(|m| m * magnitude)
+            .find(|step| *step >= raw)
+            .unwrap_or(10.0 * magnitude);
+        // The panel is only displaying integer values
+        step.max(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downsample_keeps_last_value() {
+        // 4 intervals of 10s, nothing is changing in the third one
+        let points = [(0.0, 1.0), (5.0, 3.0), (12.0, 4.0), (35.0, 8.0)];
+        assert_eq!(
+            Chart::downsample(&points, 0.0, 40.0, 4),
+            vec![2.0, 4.0, 4.0, 8.0]
+        );
+    }
+
+    #[test]
+    fn downsample_starts_from_previous_value() {
+        let points = [(-30.0, 5.0), (-10.0, 6.0), (25.0, 2.0)];
+        assert_eq!(
+            Chart::downsample(&points, 0.0, 30.0, 3),
+            vec![6.0, 6.0, 2.0]
+        );
+        assert_eq!(Chart::downsample(&[], 0.0, 30.0, 3), vec![0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn tick_step_is_rounded() {
+        assert_eq!(Chart::tick_step(0.0), 1.0);
+        assert_eq!(Chart::tick_step(0.3), 1.0);
+        assert_eq!(Chart::tick_step(1.0), 1.0);
+        assert_eq!(Chart::tick_step(1.5), 2.0);
+        assert_eq!(Chart::tick_step(3.0), 5.0);
+        assert_eq!(Chart::tick_step(7.0), 10.0);
+        assert_eq!(Chart::tick_step(12.0), 20.0);
+        assert_eq!(Chart::tick_step(450.0), 500.0);
+        assert_eq!(Chart::tick_step(5000.0), 5000.0);
+    }
+}
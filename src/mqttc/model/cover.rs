@@ -0,0 +1,260 @@
+use crate::config::schema::Config;
+use crate::homeassitant::commands::ServiceCall;
+use crate::homeassitant::entities::EntityState;
+use crate::protocol::PanelMessage;
+use serde_json::Value;
+
+/// Cover `supported_features` bits
+const COVER_OPEN: u64 = 1;
+const COVER_CLOSE: u64 = 2;
+const COVER_SET_POSITION: u64 = 4;
+const COVER_STOP: u64 = 8;
+const COVER_OPEN_TILT: u64 = 16;
+const COVER_CLOSE_TILT: u64 = 32;
+const COVER_STOP_TILT: u64 = 64;
+const COVER_SET_TILT_POSITION: u64 = 128;
+
+/// The cover detail popup (`popupShutter`) and the up/stop/down buttons shared with the
+/// `shutter` rows of the entities card.
+pub struct Cover {}
+
+impl Cover {
+    /// Build the `popupShutter` detail page. The sliders are sent as `disable` when the cover
+    /// can't be set to a position.
+    /// * Message format, the button block is `{icon}~{icon}~{icon}~{enabled}~{enabled}~{enabled}`
+    /// ```
+    /// entityUpdateDetail~{entity}~{position}~Position: {position}~Position~{icon}~{buttons}~Tilt position~{tilt buttons}~{tilt position}
+    /// ```
+    pub fn get_cover_detail(config: &Config, entity: &str, cover: &EntityState) -> PanelMessage {
+        let features = Cover::features(Some(cover));
+        let attribute = |key: &str| cover.attributes.get(key).and_then(Value::as_u64);
+        let position = attribute("current_position").filter(|_| features & COVER_SET_POSITION != 0);
+        let tilt =
+            attribute("current_tilt_position").filter(|_| features & COVER_SET_TILT_POSITION != 0);
+        let has_tilt = features
+            & (COVER_OPEN_TILT | COVER_CLOSE_TILT | COVER_STOP_TILT | COVER_SET_TILT_POSITION)
+            != 0;
+        let status = attribute("current_position").map_or(cover.state.clone(), |p| p.to_string());
+        let icon = if matches!(cover.state.as_str(), "open" | "opening") {
+            "window-shutter-open"
+        } else {
+            "window-shutter"
+        };
+        let slider = |value: Option<u64>| value.map_or("disable".to_string(), |v| v.to_string());
+
+        PanelMessage::EntityUpdateDetail {
+            entity: entity.to_string(),
+            items: [
+                vec![
+                    slider(position),
+                    format!("Position: {}", status),
+                    "Position".to_string(),
+                    Cover::icon_char(config, icon),
+                ],
+                Cover::get_buttons(config, Some(cover)).to_vec(),
+                vec![if has_tilt { "Tilt position" } else { "" }.to_string()],
+                Cover::get_tilt_buttons(config, cover).to_vec(),
+                vec![slider(tilt)],
+            ]
+            .concat(),
+        }
+    }
+
+    /// The up, stop and down buttons icons followed by `enable`/`disable` for each of them.
+    /// A button is disabled when the cover is not supporting it or is already at that end.
+    pub fn get_buttons(config: &Config, cover: Option<&EntityState>) -> [String; 6] {
+        let features = Cover::features(cover);
+        let state = cover.map_or("unavailable", |c| c.state.as_str());
+        let position = cover
+            .and_then(|c| c.attributes.get("current_position"))
+            .and_then(Value::as_u64);
+        let up = features & COVER_OPEN != 0 && position.map_or(state != "open", |p| p < 100);
+        let down = features & COVER_CLOSE != 0 && position.map_or(state != "closed", |p| p > 0);
+        let stop = features & COVER_STOP != 0;
+        [
+            Cover::icon_char(config, "arrow-up"),
+            Cover::icon_char(config, "stop"),
+            Cover::icon_char(config, "arrow-down"),
+            Cover::enabled(up),
+            Cover::enabled(stop),
+            Cover::enabled(down),
+        ]
+    }
+
+    /// Same as `Cover::get_buttons()` for the tilt, the icons are left empty when the cover
+    /// has no tilt.
+    fn get_tilt_buttons(config: &Config, cover: &EntityState) -> [String; 6] {
+        let features = Cover::features(Some(cover));
+        let tilt = cover
+            .attributes
+            .get("current_tilt_position")
+            .and_then(Value::as_u64);
+        let open = features & COVER_OPEN_TILT != 0;
+        let close = features & COVER_CLOSE_TILT != 0;
+        let stop = features & COVER_STOP_TILT != 0;
+        let icon = |supported: bool, icon: &str| {
+            if supported {
+                Cover::icon_char(config, icon)
+            } else {
+                String::default()
+            }
+        };
+        [
+            icon(open, "arrow-top-right"),
+            icon(stop, "stop"),
+            icon(close, "arrow-bottom-left"),
+            Cover::enabled(open && tilt.is_none_or(|t| t < 100)),
+            Cover::enabled(stop),
+            Cover::enabled(close && tilt.is_none_or(|t| t > 0)),
+        ]
+    }
+
+    /// Translate the cover controls into a Hass `cover` service call.
+    /// Supported actions:
+    /// * `up` / `stop` / `down` -> `cover.open_cover` / `cover.stop_cover` / `cover.close_cover`
+    /// * `tiltOpen` / `tiltStop` / `tiltClose` -> the same services for the tilt
+    /// * `positionSlider,40` -> `cover.set_cover_position` with `position: 40`
+    /// * `tiltSlider,40` -> `cover.set_cover_tilt_position` with `tilt_position: 40`
+    pub fn service_call(entity: &str, action: &str, value: &str) -> Option<ServiceCall> {
+        let service = |service: &str| ServiceCall::new("cover", service, entity);
+        let position = || {
+            value
+                .parse::<f64>()
+                .ok()
+                .map(|v| v.round().clamp(0.0, 100.0) as u64)
+        };
+        match action {
+            "up" => Some(service("open_cover")),
+            "stop" => Some(service("stop_cover")),
+            "down" => Some(service("close_cover")),
+            "tiltOpen" => Some(service("open_cover_tilt")),
+            "tiltStop" => Some(service("stop_cover_tilt")),
+            "tiltClose" => Some(service("close_cover_tilt")),
+            "positionSlider" => Some(service("set_cover_position").with("position", position()?)),
+            "tiltSlider" => {
+                Some(service("set_cover_tilt_position").with("tilt_position", position()?))
+            }
+            _ => None,
+        }
+    }
+
+    fn features(cover: Option<&EntityState>) -> u64 {
+        cover
+            .and_then(|c| c.attributes.get("supported_features"))
+            .and_then(Value::as_u64)
+            .unwrap_or_default()
+    }
+
+    fn enabled(enabled: bool) -> String {
+        if enabled { "enable" } else { "disable" }.to_string()
+    }
+
+    fn icon_char(config: &Config, icon: &str) -> String {
+        config.icons.get(icon).map_or('\0', |&c| c).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn cover(state: &str, attributes: Value) -> EntityState {
+        EntityState {
+            state: state.to_string(),
+            attributes: attributes.as_object().cloned().unwrap_or_default(),
+            last_changed: 0.0,
+        }
+    }
+
+    /// The `enable`/`disable` flags of the buttons.
+    fn enabled(buttons: &[String]) -> [&str; 3] {
+        [&buttons[3], &buttons[4], &buttons[5]].map(String::as_str)
+    }
+
+    #[test]
+    fn buttons_follow_supported_features() {
+        let config = Config::from_yaml("{}");
+        let all = json!({"supported_features": COVER_OPEN | COVER_CLOSE | COVER_STOP});
+        let buttons = Cover::get_buttons(&config, Some(&cover("stopped", all.clone())));
+        assert_eq!(enabled(&buttons), ["enable", "enable", "enable"]);
+
+        let open_close = json!({"supported_features": COVER_OPEN | COVER_CLOSE});
+        let buttons = Cover::get_buttons(&config, Some(&cover("stopped", open_close)));
+        assert_eq!(enabled(&buttons), ["enable", "disable", "enable"]);
+
+        // Already at one end
+        let buttons = Cover::get_buttons(&config, Some(&cover("open", all.clone())));
+        assert_eq!(enabled(&buttons), ["disable", "enable", "enable"]);
+        let mut closed = all;
+        closed["current_position"] = json!(0);
+        let buttons = Cover::get_buttons(&config, Some(&cover("open", closed)));
+        assert_eq!(enabled(&buttons), ["enable", "enable", "disable"]);
+
+        let buttons = Cover::get_buttons(&config, None);
+        assert_eq!(enabled(&buttons), ["disable", "disable", "disable"]);
+    }
+
+    #[test]
+    fn detail_without_position_and_tilt() {
+        let config = Config::from_yaml("{}");
+        let features = json!({"supported_features": COVER_OPEN | COVER_CLOSE});
+        let detail = Cover::get_cover_detail(&config, "cover.door", &cover("closed", features));
+        let PanelMessage::EntityUpdateDetail { entity, items } = detail else {
+            panic!("Unexpected cover detail");
+        };
+        assert_eq!(entity, "cover.door");
+        assert_eq!(items[..3], ["disable", "Position: closed", "Position"]);
+        assert_eq!(items[10], "");
+        assert_eq!(items[11..14], ["", "", ""]);
+        assert_eq!(items[17], "disable");
+    }
+
+    #[test]
+    fn detail_with_position_and_tilt() {
+        let config = Config::from_yaml("{}");
+        let attributes = json!({
+            "supported_features": COVER_SET_POSITION | COVER_OPEN_TILT | COVER_CLOSE_TILT
+                | COVER_SET_TILT_POSITION,
+            "current_position": 40,
+            "current_tilt_position": 100,
+        });
+        let detail = Cover::get_cover_detail(&config, "cover.blind", &cover("open", attributes));
+        let PanelMessage::EntityUpdateDetail { items, .. } = detail else {
+            panic!("Unexpected cover detail");
+        };
+        assert_eq!(items[..3], ["40", "Position: 40", "Position"]);
+        assert_eq!(items[10], "Tilt position");
+        // Fully tilted, only closing the tilt is possible
+        assert_eq!(enabled(&items[11..17]), ["disable", "disable", "enable"]);
+        assert_eq!(items[17], "100");
+    }
+
+    #[test]
+    fn service_call() {
+        for (action, service) in [
+            ("up", "open_cover"),
+            ("stop", "stop_cover"),
+            ("down", "close_cover"),
+            ("tiltOpen", "open_cover_tilt"),
+            ("tiltStop", "stop_cover_tilt"),
+            ("tiltClose", "close_cover_tilt"),
+        ] {
+            let service_call = Cover::service_call("cover.blind", action, "").unwrap();
+            assert_eq!(service_call.domain, "cover");
+            assert_eq!(service_call.service, service);
+            assert_eq!(service_call.service_data["entity_id"], json!("cover.blind"));
+        }
+
+        let position = Cover::service_call("cover.blind", "positionSlider", "40").unwrap();
+        assert_eq!(position.service, "set_cover_position");
+        assert_eq!(position.service_data["position"], json!(40));
+
+        let tilt = Cover::service_call("cover.blind", "tiltSlider", "120").unwrap();
+        assert_eq!(tilt.service, "set_cover_tilt_position");
+        assert_eq!(tilt.service_data["tilt_position"], json!(100));
+
+        assert!(Cover::service_call("cover.blind", "positionSlider", "half").is_none());
+        assert!(Cover::service_call("cover.blind", "toggle", "").is_none());
+    }
+}
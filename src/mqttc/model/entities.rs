@@ -0,0 +1,303 @@
+use crate::cards::Card;
+use crate::config::schema::{Cards, Config, Device, Entity, Model};
+use crate::homeassitant::commands::ServiceCall;
+use crate::homeassitant::entities::EntityState;
+use crate::mqttc::model::cover::Cover;
+use crate::mqttc::model::grid::{
+    COLOR_OFF, COLOR_ON, COLOR_UNAVAILABLE, DEFAULT_ICON, DOMAIN_ICONS,
+};
+use crate::protocol::{PanelMessage, NAVIGATION};
+use crate::state::{DeviceState, StateStore};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Domain -> (icon when on/open, icon when off/closed), for the domains not shown on grids.
+const ROW_ICONS: [(&str, &str, &str); 7] = [
+    ("sensor", "eye", "eye"),
+    ("number", "ray-vertex", "ray-vertex"),
+    ("input_number", "ray-vertex", "ray-vertex"),
+    (
+        "input_select",
+        "format-list-bulleted",
+        "format-list-bulleted",
+    ),
+    ("select", "format-list-bulleted", "format-list-bulleted"),
+    ("cover", "window-shutter-open", "window-shutter"),
+    ("input_button", "gesture-tap-button", "gesture-tap-button"),
+];
+/// Empty row
+const EMPTY_ITEM: [&str; 6] = ["delete", "", "", "", "", ""];
+
+/// The Entities card page (`cardEntities`), a list of rows with a control depending on the
+/// entity domain. Entities are paginated in groups of 4 for EU panels and 5 for US panels.
+pub struct Entities {}
+
+impl Entities {
+    /// Process the entities shown on the entities card and pass back the result into the
+    /// insert_message function. For more details look on `Entities::get_entities()` function.
+    pub fn process_entities_data<F>(
+        config: &Config,
+        store: &StateStore,
+        device: &Device,
+        entities: &BTreeMap<String, EntityState>,
+        mut insert_message: F,
+    ) where
+        F: FnMut(Card, Vec<PanelMessage>),
+    {
+        // Only the visible card and sub page can be refreshed
+        let device_state = store.get(&device.id);
+        let Some(page) = device_state
+            .page
+            .as_ref()
+            .filter(|p| p.current == Card::CardEntities)
+        else {
+            return;
+        };
+        let Some(config_card) =
+            config.get_card_at(&device.id, page.index, Card::CardEntities.as_str())
+        else {
+            return;
+        };
+        if !config_card
+            .entities
+            .iter()
+            .any(|e| entities.contains_key(&e.entity))
+        {
+            return;
+        }
+        if let Some(update) =
+            Entities::get_entities(config, device, &device_state, &config_card, page.sub_page)
+        {
+            insert_message(Card::CardEntities, vec![update]);
+        }
+    }
+
+    /// Number of rows displayed at once, depending on panel model.
+    pub fn page_size(device: &Device) -> usize {
+        match device.model {
+            Model::EU => 4,
+            Model::US => 5,
+        }
+    }
+
+    /// Number of sub pages needed to display all entities of the card.
+    pub fn sub_pages(device: &Device, config_card: &Cards) -> usize {
+        config_card
+            .entities
+            .len()
+            .div_ceil(Entities::page_size(device))
+            .max(1)
+    }
+
+    /// Build the entities page update of the card for the provided sub page.
+    /// * Message format, the entity block is repeated for each row
+    /// ```
+    /// entityUpd~{title}~1|1~{type}~{entity}~{icon}~{color}~{name}~{value}~...
+    /// ```
+    /// * Row type and value by domain
+    /// ```
+    /// light, fan                                      {domain}~...~{1|0}
+    /// switch, input_boolean, automation               switch~...~{1|0}
+    /// button, input_button, scene, script             button~...~{button text}
+    /// sensor (and unknown domains)                    text~...~{state} {unit}
+    /// number, input_number                            number~...~{value}|{min}|{max}
+    /// input_select, select                            input_sel~...~{option}
+    /// cover                                           shutter~...~{up}|{stop}|{down}|{up enabled}|{stop enabled}|{down enabled}
+    /// ```
+    pub fn get_entities(
+        config: &Config,
+        device: &Device,
+        device_state: &DeviceState,
+        config_card: &Cards,
+        sub_page: usize,
+    ) -> Option<PanelMessage> {
+        let page_size = Entities::page_size(device);
+
+        let items: Vec<String> = (0..page_size)
+            .flat_map(|i| {
+                let Some(entity) = config_card.entities.get(sub_page * page_size + i) else {
+                    return EMPTY_ITEM.map(str::to_string);
+                };
+                let stored = device_state.entities.get(&entity.entity);
+                Entities::get_row(config, entity, stored)
+            })
+            .collect();
+
+        Some(PanelMessage::EntityUpd {
+            heading: config_card.title.clone().unwrap_or_default(),
+            navigation: NAVIGATION.to_string(),
+            items,
+        })
+    }
+
+    /// Single row `{type}~{entity}~{icon}~{color}~{name}~{value}`, also used by other cards
+    /// displaying entities next to their main content.
+    pub fn get_row(config: &Config, entity: &Entity, stored: Option<&EntityState>) -> [String; 6] {
+        let state = stored.map_or("unavailable", |s| s.state.as_str());
+        let domain = entity.entity.split('.').next().unwrap_or_default();
+        let on = matches!(state, "on" | "open" | "opening");
+        let icon_char = |icon: &str| {
+            config
+                .icons
+                .get(icon.trim_start_matches("mdi:"))
+                .map_or('\0', |&c| c)
+                .to_string()
+        };
+
+        let (icon_on, icon_off) = DOMAIN_ICONS
+            .iter()
+            .chain(ROW_ICONS.iter())
+            .find(|(d, _, _)| *d == domain)
+            .map_or((DEFAULT_ICON, DEFAULT_ICON), |(_, on, off)| (*on, *off));
+        let icon = entity
+            .icon
+            .clone()
+            .unwrap_or_else(|| (if on { icon_on } else { icon_off }).to_string());
+        let color = match state {
+            "unavailable" => COLOR_UNAVAILABLE,
+            _ if on => COLOR_ON,
+            _ => COLOR_OFF,
+        };
+        let name = entity
+            .name
+            .clone()
+            .or_else(|| stored.and_then(|s| s.friendly_name().map(str::to_string)))
+            .unwrap_or_else(|| entity.entity.clone());
+        let attribute = |key: &str| stored.and_then(|s| s.attributes.get(key));
+
+        let (type_, value) = match domain {
+            // The panel is opening `popupLight`/`popupFan` for these rows
+            "light" | "fan" => (domain, (on as u8).to_string()),
+            "switch" | "input_boolean" | "automation" => ("switch", (on as u8).to_string()),
+            "button" | "input_button" => ("button", "PRESS".to_string()),
+            "scene" => ("button", "ACTIVATE".to_string()),
+            "script" => ("button", "RUN".to_string()),
+            "number" | "input_number" => {
+                let number = |key: &str, default: f64| {
+                    attribute(key).and_then(Value::as_f64).unwrap_or(default)
+                };
+                let value = state.parse::<f64>().unwrap_or_default();
+                let value = format!(
+                    "{}|{}|{}",
+                    value.round(),
+                    number("min", 0.0).round(),
+                    number("max", 100.0).round()
+                );
+                ("number", value)
+            }
+            "input_select" | "select" => ("input_sel", state.to_string()),
+            "cover" => {
+                let value = Cover::get_buttons(config, stored).join("|");
+                ("shutter", value)
+            }
+            _ => {
+                let unit = stored
+                    .and_then(|s| s.attribute_str("unit_of_measurement"))
+                    .map(|unit| format!(" {}", unit))
+                    .unwrap_or_default();
+                ("text", format!("{}{}", state, unit))
+            }
+        };
+
+        [
+            type_.to_string(),
+            entity.entity.clone(),
+            icon_char(&icon),
+            color.to_string(),
+            name,
+            value,
+        ]
+    }
+
+    /// Translate the entities card controls not known by the grid into a Hass service call.
+    /// * `number-set,{value}` -> `{domain}.set_value`
+    pub fn service_call(entity: &str, action: &str, value: Option<&str>) -> Option<ServiceCall> {
+        let domain = entity.split('.').next()?;
+        match (action, domain) {
+            ("number-set", "number" | "input_number") => {
+                let value = value?.parse::<f64>().ok()?;
+                Some(ServiceCall::new(domain, "set_value", entity).with("value", value))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(model: &str, count: usize) -> Config {
+        let entities: Vec<String> = (1..=count)
+            .map(|i| format!("{{ entity: sensor.sensor_{} }}", i))
+            .collect();
+        Config::from_yaml(&format!(
+            r#"
+            panel:
+              module: nspanel
+              id: panel
+              mqtt: {{ rx_topic: cmnd/panel/CustomSend, tx_topic: tele/panel/RESULT }}
+              model: {}
+              config: {{ timeout_to_screensaver: 20, screensaver_brightness: [], locale: en_US,
+                        timezone: Europe/Bucharest }}
+              cards:
+                - {{ type: cardEntities, title: Sensors, entities: [ {} ] }}
+            "#,
+            model,
+            entities.join(", ")
+        ))
+    }
+
+    /// The entities of the rows, empty for the unused ones.
+    fn rows(config: &Config, sub_page: usize) -> Vec<String> {
+        let device = &config.devices["panel"];
+        let card = &device.get_cards()[0];
+        let Some(PanelMessage::EntityUpd { items, .. }) =
+            Entities::get_entities(config, device, &DeviceState::default(), card, sub_page)
+        else {
+            panic!("Missing entities update");
+        };
+        items.chunks(6).map(|row| row[1].clone()).collect()
+    }
+
+    #[test]
+    fn eu_page_has_4_rows() {
+        let config = config("EU", 6);
+        let device = &config.devices["panel"];
+        assert_eq!(Entities::sub_pages(device, &device.get_cards()[0]), 2);
+        assert_eq!(
+            rows(&config, 0),
+            [
+                "sensor.sensor_1",
+                "sensor.sensor_2",
+                "sensor.sensor_3",
+                "sensor.sensor_4"
+            ]
+        );
+        assert_eq!(
+            rows(&config, 1),
+            ["sensor.sensor_5", "sensor.sensor_6", "", ""]
+        );
+    }
+
+    #[test]
+    fn us_page_has_5_rows() {
+        let config = config("US", 6);
+        let device = &config.devices["panel"];
+        assert_eq!(Entities::sub_pages(device, &device.get_cards()[0]), 2);
+        assert_eq!(
+            rows(&config, 0),
+            (1..=5)
+                .map(|i| format!("sensor.sensor_{}", i))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(rows(&config, 1), ["sensor.sensor_6", "", "", "", ""]);
+    }
+
+    #[test]
+    fn exact_fit_has_no_empty_page() {
+        let config = config("US", 5);
+        let device = &config.devices["panel"];
+        assert_eq!(Entities::sub_pages(device, &device.get_cards()[0]), 1);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::homeassitant::commands::ServiceCall;
+use crate::homeassitant::entities::EntityState;
+use crate::mqttc::model::grid::{COLOR_OFF, COLOR_ON, COLOR_UNAVAILABLE};
+use crate::protocol::PanelMessage;
+use crate::state::StateStore;
+use serde_json::Value;
+
+/// The fan detail popup (`popupFan`), with the speed slider and the preset modes.
+pub struct Fan {}
+
+impl Fan {
+    /// Build the `popupFan` detail page. The speed is counted in `percentage_step` steps, the
+    /// slider is sent as `disable` when the fan has no speed.
+    /// * Message format
+    /// ```
+    /// entityUpdateDetail~{entity}~~{color}~{1|0}~{speed}~{max speed}~Speed~{preset}~{preset?preset}
+    /// ```
+    pub fn get_fan_detail(entity: &str, fan: &EntityState) -> PanelMessage {
+        let color = match fan.state.as_str() {
+            "unavailable" => COLOR_UNAVAILABLE,
+            "on" => COLOR_ON,
+            _ => COLOR_OFF,
+        };
+        let (speed, max_speed) = match Fan::percentage_step(fan) {
+            Some(step) => {
+                let percentage = fan
+                    .attributes
+                    .get("percentage")
+                    .and_then(Value::as_f64)
+                    .unwrap_or_default();
+                (
+                    (percentage / step).round().to_string(),
+                    (100.0 / step).round().to_string(),
+                )
+            }
+            None => ("disable".to_string(), "100".to_string()),
+        };
+
+        PanelMessage::EntityUpdateDetail {
+            entity: entity.to_string(),
+            items: vec![
+                String::default(),
+                color.to_string(),
+                ((fan.state == "on") as u8).to_string(),
+                speed,
+                max_speed,
+                "Speed".to_string(),
+                fan.attribute_str("preset_mode")
+                    .unwrap_or_default()
+                    .to_string(),
+                Fan::preset_modes(fan).join("?"),
+            ],
+        }
+    }
+
+    /// Translate the fan popup controls into a Hass `fan` service call.
+    /// Supported actions:
+    /// * `number-set,3` -> `fan.set_percentage` with 3 times `percentage_step`
+    /// * `mode-preset_modes,2` -> `fan.set_preset_mode` using the preset at index 2
+    pub fn service_call(
+        store: &StateStore,
+        device_id: &str,
+        entity: &str,
+        action: &str,
+        value: &str,
+    ) -> Option<ServiceCall> {
+        let service = |service: &str| ServiceCall::new("fan", service, entity);
+        let fan = store.get(device_id).entities.get(entity)?.clone();
+        match action {
+            "number-set" => {
+                let speed = value.parse::<f64>().ok()?;
+                let percentage = (speed * Fan::percentage_step(&fan)?).round();
+                Some(service("set_percentage").with("percentage", percentage.clamp(0.0, 100.0)))
+            }
+            "mode-preset_modes" => {
+                let index: usize = value.parse().ok()?;
+                let preset = Fan::preset_modes(&fan).get(index)?.clone();
+                Some(service("set_preset_mode").with("preset_mode", preset))
+            }
+            _ => None,
+        }
+    }
+
+    /// Speed increment in percent, `None` when the fan has no speed.
+    fn percentage_step(fan: &EntityState) -> Option<f64> {
+        fan.attributes
+            .get("percentage_step")
+            .and_then(Value::as_f64)
+            .filter(|step| *step > 0.0)
+    }
+
+    fn preset_modes(fan: &EntityState) -> Vec<String> {
+        fan.attributes
+            .get("preset_modes")
+            .and_then(Value::as_array)
+            .map(|modes| {
+                modes
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    fn fan(state: &str, attributes: Value) -> EntityState {
+        EntityState {
+            state: state.to_string(),
+            attributes: attributes.as_object().cloned().unwrap_or_default(),
+            last_changed: 0.0,
+        }
+    }
+
+    fn store(fan: EntityState) -> StateStore {
+        let store = StateStore::new();
+        store.apply_states("panel", BTreeMap::from([("fan.ceiling".to_string(), fan)]));
+        store
+    }
+
+    #[test]
+    fn detail_counts_speed_in_steps() {
+        let attributes = json!({
+            "percentage": 66,
+            "percentage_step": 33.333333333333336,
+            "preset_mode": "breeze",
+            "preset_modes": ["auto", "breeze"],
+        });
+        let detail = Fan::get_fan_detail("fan.ceiling", &fan("on", attributes));
+        assert_eq!(
+            detail.to_string(),
+            format!(
+                "entityUpdateDetail~fan.ceiling~~{}~1~2~3~Speed~breeze~auto?breeze",
+                COLOR_ON
+            )
+        );
+
+        let detail = Fan::get_fan_detail("fan.ceiling", &fan("off", json!({})));
+        assert_eq!(
+            detail.to_string(),
+            format!(
+                "entityUpdateDetail~fan.ceiling~~{}~0~disable~100~Speed~~",
+                COLOR_OFF
+            )
+        );
+    }
+
+    #[test]
+    fn service_call_percentage() {
+        let stepped = store(fan("on", json!({"percentage_step": 25.0})));
+        let service =
+            Fan::service_call(&stepped, "panel", "fan.ceiling", "number-set", "3").unwrap();
+        assert_eq!(service.domain, "fan");
+        assert_eq!(service.service, "set_percentage");
+        assert_eq!(service.service_data["percentage"], json!(75.0));
+
+        let service =
+            Fan::service_call(&stepped, "panel", "fan.ceiling", "number-set", "5").unwrap();
+        assert_eq!(service.service_data["percentage"], json!(100.0));
+
+        // Without speed support
+        let no_speed = store(fan("on", json!({"percentage_step": 0})));
+        assert!(Fan::service_call(&no_speed, "panel", "fan.ceiling", "number-set", "1").is_none());
+    }
+
+    #[test]
+    fn service_call_preset() {
+        let store = store(fan("on", json!({"preset_modes": ["auto", "breeze"]})));
+        let service =
+            Fan::service_call(&store, "panel", "fan.ceiling", "mode-preset_modes", "1").unwrap();
+        assert_eq!(service.service, "set_preset_mode");
+        assert_eq!(service.service_data["preset_mode"], json!("breeze"));
+
+        assert!(
+            Fan::service_call(&store, "panel", "fan.ceiling", "mode-preset_modes", "2").is_none()
+        );
+        assert!(
+            Fan::service_call(&store, "panel", "fan.other", "mode-preset_modes", "0").is_none()
+        );
+    }
+}
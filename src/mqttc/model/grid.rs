@@ -0,0 +1,263 @@
+use crate::cards::Card;
+use crate::config::schema::{Cards, Config, Device, Model};
+use crate::homeassitant::commands::ServiceCall;
+use crate::homeassitant::entities::EntityState;
+use crate::protocol::{PanelMessage, NAVIGATION};
+use crate::state::{DeviceState, StateStore};
+use std::collections::BTreeMap;
+
+/// Domain -> (icon when on, icon when off)
+pub(crate) const DOMAIN_ICONS: [(&str, &str, &str); 8] = [
+    ("light", "lightbulb", "lightbulb-outline"),
+    ("switch", "power-plug", "power-plug-off"),
+    (
+        "input_boolean",
+        "check-circle-outline",
+        "close-circle-outline",
+    ),
+    ("fan", "fan", "fan-off"),
+    ("automation", "robot", "robot"),
+    ("script", "script-text", "script-text"),
+    ("scene", "palette", "palette"),
+    ("button", "gesture-tap-button", "gesture-tap-button"),
+];
+pub(crate) const DEFAULT_ICON: &str = "alert-circle-outline";
+pub(crate) const COLOR_ON: u32 = 64909;
+pub(crate) const COLOR_OFF: u32 = 17299;
+pub(crate) const COLOR_UNAVAILABLE: u32 = 38066;
+/// Empty grid cell
+const EMPTY_ITEM: [&str; 6] = ["delete", "", "", "", "", ""];
+
+/// The Grid card page (`cardGrid`, also used to display `cardHome`).
+/// Entities are paginated in groups of 6 for EU panels and 4 for US panels.
+pub struct Grid {}
+
+impl Grid {
+    /// Process the entities shown on grid cards and pass back the result into the
+    /// insert_message function. For more details look on `Grid::get_grid()` function.
+    pub fn process_entities_data<F>(
+        config: &Config,
+        store: &StateStore,
+        device: &Device,
+        entities: &BTreeMap<String, EntityState>,
+        mut insert_message: F,
+    ) where
+        F: FnMut(Card, Vec<PanelMessage>),
+    {
+        // Only the visible card and sub page can be refreshed
+        let device_state = store.get(&device.id);
+        let Some(page) = device_state
+            .page
+            .as_ref()
+            .filter(|p| Grid::is_grid(&p.current))
+        else {
+            return;
+        };
+        let Some(config_card) = config.get_card_at(&device.id, page.index, page.current.as_str())
+        else {
+            return;
+        };
+        if !config_card
+            .entities
+            .iter()
+            .any(|e| entities.contains_key(&e.entity))
+        {
+            return;
+        }
+        if let Some(update) =
+            Grid::get_grid(config, device, &device_state, &config_card, page.sub_page)
+        {
+            insert_message(page.current.clone(), vec![update]);
+        }
+    }
+
+    pub fn is_grid(card: &Card) -> bool {
+        matches!(card, Card::CardHome | Card::CardGrid)
+    }
+
+    /// Number of entities displayed at once, depending on panel model.
+    pub fn page_size(device: &Device) -> usize {
+        match device.model {
+            Model::EU => 6,
+            Model::US => 4,
+        }
+    }
+
+    /// Number of sub pages needed to display all entities of the card.
+    pub fn sub_pages(device: &Device, config_card: &Cards) -> usize {
+        config_card
+            .entities
+            .len()
+            .div_ceil(Grid::page_size(device))
+            .max(1)
+    }
+
+    /// Build the grid page update of the card for the provided sub page.
+    /// * Message format, the entity block is repeated for each grid cell
+    /// ```
+    /// entityUpd~{title}~1|1~{type}~{entity}~{icon}~{color}~{name}~{value}~...
+    /// ```
+    pub fn get_grid(
+        config: &Config,
+        device: &Device,
+        device_state: &DeviceState,
+        config_card: &Cards,
+        sub_page: usize,
+    ) -> Option<PanelMessage> {
+        let page_size = Grid::page_size(device);
+
+        let items: Vec<String> = (0..page_size)
+            .flat_map(|i| {
+                let Some(entity) = config_card.entities.get(sub_page * page_size + i) else {
+                    return EMPTY_ITEM.map(str::to_string);
+                };
+                let stored = device_state.entities.get(&entity.entity);
+                let state = stored.map_or("unavailable", |s| s.state.as_str());
+                let domain = entity.entity.split('.').next().unwrap_or_default();
+                let on = state == "on";
+
+                let (icon_on, icon_off) = DOMAIN_ICONS
+                    .iter()
+                    .find(|(d, _, _)| *d == domain)
+                    .map_or((DEFAULT_ICON, DEFAULT_ICON), |(_, on, off)| (*on, *off));
+                let icon = entity
+                    .icon
+                    .clone()
+                    .unwrap_or_else(|| (if on { icon_on } else { icon_off }).to_string());
+                let color = match state {
+                    "on" => COLOR_ON,
+                    "unavailable" => COLOR_UNAVAILABLE,
+                    _ => COLOR_OFF,
+                };
+                let name = entity
+                    .name
+                    .clone()
+                    .or_else(|| stored.and_then(|s| s.friendly_name().map(str::to_string)))
+                    .unwrap_or_else(|| entity.entity.clone());
+                // Buttons are only pressed, everything else is switched
+                let (type_, value) = match domain {
+                    "button" | "input_button" | "scene" | "script" => ("button", String::default()),
+                    _ => (domain, (on as u8).to_string()),
+                };
+
+                [
+                    type_.to_string(),
+                    entity.entity.clone(),
+                    config
+                        .icons
+                        .get(icon.trim_start_matches("mdi:"))
+                        .map_or('\0', |&c| c)
+                        .to_string(),
+                    color.to_string(),
+                    name,
+                    value,
+                ]
+            })
+            .collect();
+
+        Some(PanelMessage::EntityUpd {
+            heading: config_card.title.clone().unwrap_or_default(),
+            navigation: NAVIGATION.to_string(),
+            items,
+        })
+    }
+
+    /// Translate a grid button press into a Hass service call.
+    /// * `OnOff,1` / `OnOff,0` -> `{domain}.turn_on` / `{domain}.turn_off`
+    /// * `OnOff` without value -> `{domain}.toggle`
+    /// * `button` -> `{domain}.press` for buttons, `{domain}.turn_on` for scenes and scripts
+    ///   and `{domain}.toggle` for everything else
+    pub fn service_call(entity: &str, action: &str, value: Option<&str>) -> Option<ServiceCall> {
+        let domain = entity.split('.').next()?;
+        let service = match (action, value, domain) {
+            ("OnOff", Some("1"), _) => "turn_on",
+            ("OnOff", Some("0"), _) => "turn_off",
+            ("OnOff", _, _) => "toggle",
+            ("button", _, "button" | "input_button") => "press",
+            ("button", _, "scene" | "script") => "turn_on",
+            ("button", _, _) => "toggle",
+            _ => return None,
+        };
+        Some(ServiceCall::new(domain, service, entity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(model: &str, count: usize) -> Config {
+        let entities: Vec<String> = (1..=count)
+            .map(|i| format!("{{ entity: light.light_{} }}", i))
+            .collect();
+        Config::from_yaml(&format!(
+            r#"
+            panel:
+              module: nspanel
+              id: panel
+              mqtt: {{ rx_topic: cmnd/panel/CustomSend, tx_topic: tele/panel/RESULT }}
+              model: {}
+              config: {{ timeout_to_screensaver: 20, screensaver_brightness: [], locale: en_US,
+                        timezone: Europe/Bucharest }}
+              cards:
+                - {{ type: cardGrid, title: Lights, entities: [ {} ] }}
+            "#,
+            model,
+            entities.join(", ")
+        ))
+    }
+
+    /// The entities of the grid cells, empty for the unused ones.
+    fn cells(config: &Config, sub_page: usize) -> Vec<String> {
+        let device = &config.devices["panel"];
+        let card = &device.get_cards()[0];
+        let Some(PanelMessage::EntityUpd { items, .. }) =
+            Grid::get_grid(config, device, &DeviceState::default(), card, sub_page)
+        else {
+            panic!("Missing grid update");
+        };
+        items.chunks(6).map(|cell| cell[1].clone()).collect()
+    }
+
+    #[test]
+    fn eu_grid_has_6_cells() {
+        let config = config("EU", 8);
+        let device = &config.devices["panel"];
+        assert_eq!(Grid::sub_pages(device, &device.get_cards()[0]), 2);
+        assert_eq!(
+            cells(&config, 0),
+            (1..=6)
+                .map(|i| format!("light.light_{}", i))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            cells(&config, 1),
+            ["light.light_7", "light.light_8", "", "", "", ""]
+        );
+    }
+
+    #[test]
+    fn us_grid_has_4_cells() {
+        let config = config("US", 9);
+        let device = &config.devices["panel"];
+        assert_eq!(Grid::sub_pages(device, &device.get_cards()[0]), 3);
+        assert_eq!(
+            cells(&config, 1),
+            [
+                "light.light_5",
+                "light.light_6",
+                "light.light_7",
+                "light.light_8"
+            ]
+        );
+        assert_eq!(cells(&config, 2), ["light.light_9", "", "", ""]);
+    }
+
+    #[test]
+    fn empty_grid_has_one_page() {
+        let config = config("EU", 0);
+        let device = &config.devices["panel"];
+        assert_eq!(Grid::sub_pages(device, &device.get_cards()[0]), 1);
+        assert_eq!(cells(&config, 0), ["", "", "", "", "", ""]);
+    }
+}
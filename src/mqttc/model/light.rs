@@ -0,0 +1,303 @@
+use crate::homeassitant::commands::ServiceCall;
+use crate::homeassitant::entities::EntityState;
+use crate::mqttc::model::grid::{COLOR_OFF, COLOR_ON, COLOR_UNAVAILABLE};
+use crate::protocol::PanelMessage;
+use crate::state::StateStore;
+use serde_json::Value;
+
+/// Color modes that can be set from the color wheel.
+const WHEEL_MODES: [&str; 5] = ["hs", "xy", "rgb", "rgbw", "rgbww"];
+
+/// The light detail popup (`popupLight`), opened by a long press on a light of any card.
+pub struct Light {}
+
+impl Light {
+    /// Build the `popupLight` detail page. Sliders are sent as `disable` when the light is not
+    /// supporting them, the color temperature is `unknown` while the light is not in that mode.
+    /// * Message format, brightness and color temperature are between 0 and 100
+    /// ```
+    /// entityUpdateDetail~{entity}~~{color}~{1|0}~{brightness}~{color temp}~{enable|disable}~Color~Color temperature~Brightness~disable
+    /// ```
+    pub fn get_light_detail(entity: &str, light: &EntityState) -> PanelMessage {
+        let on = light.state == "on";
+        let modes: Vec<&str> = light
+            .attributes
+            .get("supported_color_modes")
+            .and_then(Value::as_array)
+            .map(|modes| modes.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let attribute = |key: &str| light.attributes.get(key).and_then(Value::as_f64);
+
+        let color = match light.state.as_str() {
+            "unavailable" => COLOR_UNAVAILABLE,
+            "on" => light
+                .attributes
+                .get("rgb_color")
+                .and_then(Value::as_array)
+                .and_then(|rgb| Light::rgb565(rgb))
+                .unwrap_or(COLOR_ON),
+            _ => COLOR_OFF,
+        };
+        let brightness = if modes.iter().all(|mode| *mode == "onoff") {
+            "disable".to_string()
+        } else {
+            attribute("brightness")
+                .filter(|_| on)
+                .map_or(0.0, |b| b * 100.0 / 255.0)
+                .round()
+                .to_string()
+        };
+        let color_temp = if !modes.contains(&"color_temp") {
+            "disable".to_string()
+        } else {
+            Light::color_temp_position(light)
+                .filter(|_| on)
+                .map_or("unknown".to_string(), |p| p.round().to_string())
+        };
+        let wheel = modes.iter().any(|mode| WHEEL_MODES.contains(mode));
+
+        PanelMessage::EntityUpdateDetail {
+            entity: entity.to_string(),
+            items: vec![
+                String::default(),
+                color.to_string(),
+                (on as u8).to_string(),
+                brightness,
+                color_temp,
+                if wheel { "enable" } else { "disable" }.to_string(),
+                "Color".to_string(),
+                "Color temperature".to_string(),
+                "Brightness".to_string(),
+                "disable".to_string(),
+            ],
+        }
+    }
+
+    /// Translate the light popup controls into a `light.turn_on` service call.
+    /// Supported actions:
+    /// * `brightnessSlider,40` -> `brightness: 102`, Hass is using 0 to 255
+    /// * `colorTempSlider,0` -> the warmest `color_temp_kelvin`, or `color_temp` in mireds
+    ///   for the lights not reporting kelvin
+    /// * `colorWheel,{x}|{y}|{wheel size}` -> `hs_color` of the touched point
+    pub fn service_call(
+        store: &StateStore,
+        device_id: &str,
+        entity: &str,
+        action: &str,
+        value: &str,
+    ) -> Option<ServiceCall> {
+        let service = ServiceCall::new("light", "turn_on", entity);
+        match action {
+            "brightnessSlider" => {
+                let value = value.parse::<f64>().ok()?.clamp(0.0, 100.0);
+                Some(service.with("brightness", (value * 255.0 / 100.0).round() as u64))
+            }
+            "colorTempSlider" => {
+                let position = value.parse::<f64>().ok()?.clamp(0.0, 100.0) / 100.0;
+                let light = store.get(device_id).entities.get(entity)?.clone();
+                let attribute = |key: &str| light.attributes.get(key).and_then(Value::as_f64);
+                if let (Some(min), Some(max)) = (
+                    attribute("min_color_temp_kelvin"),
+                    attribute("max_color_temp_kelvin"),
+                ) {
+                    let kelvin = min + position * (max - min);
+                    return Some(service.with("color_temp_kelvin", kelvin.round() as u64));
+                }
+                let min = attribute("min_mireds")?;
+                let max = attribute("max_mireds")?;
+                // The warmest color has the most mireds
+                let mireds = max - position * (max - min);
+                Some(service.with("color_temp", mireds.round() as u64))
+            }
+            "colorWheel" => {
+                let (hue, saturation) = Light::wheel_to_hs(value)?;
+                Some(service.with("hs_color", vec![hue, saturation]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Position of the color temperature slider, 0 being the warmest color.
+    fn color_temp_position(light: &EntityState) -> Option<f64> {
+        let attribute = |key: &str| light.attributes.get(key).and_then(Value::as_f64);
+        let (value, min, max, warm_first) = match attribute("color_temp_kelvin") {
+            Some(kelvin) => (
+                kelvin,
+                attribute("min_color_temp_kelvin")?,
+                attribute("max_color_temp_kelvin")?,
+                true,
+            ),
+            None => (
+                attribute("color_temp")?,
+                attribute("min_mireds")?,
+                attribute("max_mireds")?,
+                false,
+            ),
+        };
+        if max <= min {
+            return None;
+        }
+        let position = ((value - min) / (max - min)).clamp(0.0, 1.0) * 100.0;
+        Some(if warm_first {
+            position
+        } else {
+            100.0 - position
+        })
+    }
+
+    /// The wheel is a `{wheel size}` pixels square with the white in the middle, the hue is
+    /// the angle of the touched point and the saturation its distance to the middle.
+    fn wheel_to_hs(value: &str) -> Option<(f64, f64)> {
+        let mut coordinates = value.split('|').map(|v| v.parse::<f64>().ok());
+        let (x, y, size) = (
+            coordinates.next()??,
+            coordinates.next()??,
+            coordinates.next()??,
+        );
+        let radius = size / 2.0;
+        if radius <= 0.0 {
+            return None;
+        }
+        let x = (x - radius) / radius;
+        let y = (radius - y) / radius;
+        let hue = y.atan2(x).to_degrees().rem_euclid(360.0);
+        let saturation = x.hypot(y).min(1.0) * 100.0;
+        Some((hue.round(), saturation.round()))
+    }
+
+    /// `[r, g, b]` -> rgb565 color used by the panel.
+    fn rgb565(rgb: &[Value]) -> Option<u32> {
+        let channel = |i: usize| {
+            rgb.get(i)
+                .and_then(Value::as_u64)
+                .map(|c| c.min(255) as u32)
+        };
+        let (r, g, b) = (channel(0)?, channel(1)?, channel(2)?);
+        Some(((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::DeviceState;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    fn light(attributes: Value) -> EntityState {
+        EntityState {
+            state: "on".into(),
+            attributes: attributes.as_object().cloned().unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn wheel_to_hs_from_coordinates() {
+        // The middle of the wheel is white
+        assert_eq!(Light::wheel_to_hs("100|100|200"), Some((0.0, 0.0)));
+        // Edges
+        assert_eq!(Light::wheel_to_hs("200|100|200"), Some((0.0, 100.0)));
+        assert_eq!(Light::wheel_to_hs("100|0|200"), Some((90.0, 100.0)));
+        assert_eq!(Light::wheel_to_hs("0|100|200"), Some((180.0, 100.0)));
+        assert_eq!(Light::wheel_to_hs("100|200|200"), Some((270.0, 100.0)));
+        // Quadrants, the y axis of the panel is going down
+        assert_eq!(Light::wheel_to_hs("150|50|200"), Some((45.0, 71.0)));
+        assert_eq!(Light::wheel_to_hs("50|50|200"), Some((135.0, 71.0)));
+        assert_eq!(Light::wheel_to_hs("50|150|200"), Some((225.0, 71.0)));
+        assert_eq!(Light::wheel_to_hs("150|150|200"), Some((315.0, 71.0)));
+        // Corners are outside of the wheel
+        assert_eq!(Light::wheel_to_hs("200|0|200"), Some((45.0, 100.0)));
+
+        assert_eq!(Light::wheel_to_hs("100|100"), None);
+        assert_eq!(Light::wheel_to_hs("0|0|0"), None);
+    }
+
+    #[test]
+    fn color_temp_warm_is_first() {
+        let kelvin = |value: u64| {
+            light(json!({
+                "color_temp_kelvin": value,
+                "min_color_temp_kelvin": 2000,
+                "max_color_temp_kelvin": 6500,
+            }))
+        };
+        assert_eq!(Light::color_temp_position(&kelvin(2000)), Some(0.0));
+        assert_eq!(Light::color_temp_position(&kelvin(6500)), Some(100.0));
+
+        let mireds = |value: u64| {
+            light(json!({
+                "color_temp": value,
+                "min_mireds": 150,
+                "max_mireds": 500,
+            }))
+        };
+        assert_eq!(Light::color_temp_position(&mireds(500)), Some(0.0));
+        assert_eq!(Light::color_temp_position(&mireds(150)), Some(100.0));
+        assert_eq!(Light::color_temp_position(&light(json!({}))), None);
+    }
+
+    #[test]
+    fn service_call_conversions() {
+        let store = StateStore::new();
+        let entities = BTreeMap::from([
+            (
+                "light.kelvin".to_string(),
+                light(json!({"min_color_temp_kelvin": 2000, "max_color_temp_kelvin": 6500})),
+            ),
+            (
+                "light.mireds".to_string(),
+                light(json!({"min_mireds": 150, "max_mireds": 500})),
+            ),
+        ]);
+        store.update(
+            "panel",
+            DeviceState {
+                entities,
+                ..Default::default()
+            },
+        );
+        let data = |entity: &str, action: &str, value: &str, key: &str| {
+            Light::service_call(&store, "panel", entity, action, value)
+                .and_then(|call| call.service_data.get(key).cloned())
+        };
+
+        assert_eq!(
+            data("light.kelvin", "brightnessSlider", "0", "brightness"),
+            Some(json!(0))
+        );
+        assert_eq!(
+            data("light.kelvin", "brightnessSlider", "40", "brightness"),
+            Some(json!(102))
+        );
+        assert_eq!(
+            data("light.kelvin", "brightnessSlider", "100", "brightness"),
+            Some(json!(255))
+        );
+        assert_eq!(
+            data("light.kelvin", "colorTempSlider", "0", "color_temp_kelvin"),
+            Some(json!(2000))
+        );
+        assert_eq!(
+            data(
+                "light.kelvin",
+                "colorTempSlider",
+                "100",
+                "color_temp_kelvin"
+            ),
+            Some(json!(6500))
+        );
+        assert_eq!(
+            data("light.mireds", "colorTempSlider", "0", "color_temp"),
+            Some(json!(500))
+        );
+        assert_eq!(
+            data("light.mireds", "colorTempSlider", "100", "color_temp"),
+            Some(json!(150))
+        );
+        assert_eq!(
+            data("light.kelvin", "colorWheel", "100|0|200", "hs_color"),
+            Some(json!([90.0, 100.0]))
+        );
+    }
+}
@@ -0,0 +1,344 @@
+use crate::cards::Card;
+use crate::config::schema::{Config, Device};
+use crate::homeassitant::commands::ServiceCall;
+use crate::homeassitant::entities::EntityState;
+use crate::mqttc::model::entities::Entities;
+use crate::mqttc::model::grid::{COLOR_OFF, COLOR_ON};
+use crate::protocol::{PanelMessage, NAVIGATION};
+use crate::state::{DeviceState, StateStore};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Media player `supported_features` bits
+const SUPPORT_TURN_ON: u64 = 128;
+const SUPPORT_TURN_OFF: u64 = 256;
+const SUPPORT_SELECT_SOURCE: u64 = 2048;
+const SUPPORT_SHUFFLE_SET: u64 = 32768;
+/// Sent instead of a color or icon to hide a button.
+const DISABLED: &str = "disable";
+
+/// The Media card page (`cardMedia`), controlling the `media_player` configured as the first
+/// entity of the card. The other entities are shown as extra buttons.
+pub struct Media {}
+
+impl Media {
+    /// Process the media player data and pass back the result into the insert_message function
+    /// For more details look on `Media::get_media()` function.
+    pub fn process_media_data<F>(
+        config: &Config,
+        store: &StateStore,
+        device: &Device,
+        entities: &BTreeMap<String, EntityState>,
+        mut insert_message: F,
+    ) where
+        F: FnMut(Card, Vec<PanelMessage>),
+    {
+        let Some(card) = config.get_card_by_name(&device.id, Card::CardMedia.as_str()) else {
+            return;
+        };
+        if !card
+            .entities
+            .iter()
+            .any(|e| entities.contains_key(&e.entity))
+        {
+            return;
+        }
+        if let Some(update) = Media::get_media(config, device, &store.get(&device.id)) {
+            insert_message(Card::CardMedia, vec![update]);
+        }
+    }
+
+    /// Build the media page update.
+    /// * Message format, the source selection and the extra entities are sent as rows
+    ///   `{type}~{entity}~{icon}~{color}~{name}~{value}`.
+    /// ```
+    /// entityUpd~{title}~1|1~{entity}~{media title}~~{artist}~~{volume}~{play/pause icon}
+    /// ~{on/off color}~{shuffle icon}~{source row}~{rows}...
+    /// ```
+    pub fn get_media(
+        config: &Config,
+        device: &Device,
+        device_state: &DeviceState,
+    ) -> Option<PanelMessage> {
+        let card = config.get_card_by_name(&device.id, Card::CardMedia.as_str())?;
+        let (media, extra) = card.entities.split_first()?;
+        let player = device_state.entities.get(&media.entity)?;
+        let icon = |icon: &str| config.icons.get(icon).map_or('\0', |&c| c).to_string();
+        let features = player
+            .attributes
+            .get("supported_features")
+            .and_then(Value::as_u64)
+            .unwrap_or_default();
+
+        let volume = player
+            .attributes
+            .get("volume_level")
+            .and_then(Value::as_f64)
+            .map_or(0, |v| (v * 100.0).round() as u16);
+        let play_pause = if player.state == "playing" {
+            "pause"
+        } else {
+            "play"
+        };
+        let on_off = match player.state.as_str() {
+            _ if features & (SUPPORT_TURN_ON | SUPPORT_TURN_OFF) == 0 => DISABLED.to_string(),
+            "off" => COLOR_OFF.to_string(),
+            _ => COLOR_ON.to_string(),
+        };
+        let shuffle = match player.attributes.get("shuffle").and_then(Value::as_bool) {
+            _ if features & SUPPORT_SHUFFLE_SET == 0 => DISABLED.to_string(),
+            Some(true) => icon("shuffle"),
+            _ => icon("shuffle-disabled"),
+        };
+
+        let mut items = vec![
+            media.entity.clone(),
+            player
+                .attribute_str("media_title")
+                .unwrap_or_default()
+                .to_string(),
+            String::default(),
+            player
+                .attribute_str("media_artist")
+                .unwrap_or_default()
+                .to_string(),
+            String::default(),
+            volume.to_string(),
+            icon(play_pause),
+            on_off,
+            shuffle,
+        ];
+        if features & SUPPORT_SELECT_SOURCE != 0 && !Media::sources(player).is_empty() {
+            let source = player.attribute_str("source").unwrap_or_default();
+            items.extend([
+                "media_pl".to_string(),
+                media.entity.clone(),
+                icon("speaker"),
+                COLOR_OFF.to_string(),
+                source.to_string(),
+                source.to_string(),
+            ]);
+        }
+        for entity in extra {
+            let stored = device_state.entities.get(&entity.entity);
+            items.extend(Entities::get_row(config, entity, stored));
+        }
+
+        Some(PanelMessage::EntityUpd {
+            heading: card
+                .title
+                .or_else(|| player.friendly_name().map(str::to_string))
+                .unwrap_or_default(),
+            navigation: NAVIGATION.to_string(),
+            items,
+        })
+    }
+
+    /// Build the `popupInSel` option list with the player sources.
+    /// * Message format
+    /// ```
+    /// entityUpdateDetail2~{entity}~~{color}~media_pl~{source}~{source?source}~
+    /// ```
+    pub fn get_source_detail(entity: &str, player: &EntityState) -> PanelMessage {
+        PanelMessage::EntityUpdateDetail2 {
+            entity: entity.to_string(),
+            items: vec![
+                String::default(),
+                COLOR_OFF.to_string(),
+                "media_pl".to_string(),
+                player
+                    .attribute_str("source")
+                    .unwrap_or_default()
+                    .to_string(),
+                Media::sources(player).join("?"),
+                String::default(),
+            ],
+        }
+    }
+
+    /// Translate a media page button press into a Hass `media_player` service call.
+    /// Supported actions:
+    /// * `media-back` / `media-pause` / `media-next` -> previous track, play/pause, next track
+    /// * `media-OnOff` -> `media_player.toggle`
+    /// * `media-shuffle` -> `media_player.shuffle_set` with the opposite of the current value
+    /// * `volumeSlider,45` -> `media_player.volume_set` with `volume_level: 0.45`
+    /// * `mode-media_pl,2` -> `media_player.select_source` using the source at index 2
+    pub fn service_call(
+        store: &StateStore,
+        device_id: &str,
+        entity: &str,
+        action: &str,
+        value: &str,
+    ) -> Option<ServiceCall> {
+        let service = |service: &str| ServiceCall::new("media_player", service, entity);
+        let player = || store.get(device_id).entities.get(entity).cloned();
+        match action {
+            "media-back" => Some(service("media_previous_track")),
+            "media-pause" => Some(service("media_play_pause")),
+            "media-next" => Some(service("media_next_track")),
+            "media-OnOff" => Some(service("toggle")),
+            "media-shuffle" => {
+                let shuffle = player()?.attributes.get("shuffle").and_then(Value::as_bool);
+                Some(service("shuffle_set").with("shuffle", !shuffle.unwrap_or_default()))
+            }
+            "volumeSlider" => {
+                let volume = value.parse::<f64>().ok()?.clamp(0.0, 100.0) / 100.0;
+                Some(service("volume_set").with("volume_level", volume))
+            }
+            "mode-media_pl" => {
+                let index: usize = value.parse().ok()?;
+                let source = Media::sources(&player()?).get(index)?.clone();
+                Some(service("select_source").with("source", source))
+            }
+            _ => None,
+        }
+    }
+
+    fn sources(player: &EntityState) -> Vec<String> {
+        player
+            .attributes
+            .get("source_list")
+            .and_then(Value::as_array)
+            .map(|sources| {
+                sources
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const DEVICES: &str = r#"
+        panel:
+          module: nspanel
+          id: panel
+          mqtt: { rx_topic: cmnd/panel/CustomSend, tx_topic: tele/panel/RESULT }
+          model: EU
+          config: { timeout_to_screensaver: 20, screensaver_brightness: [], locale: en_US,
+                    timezone: Europe/Bucharest }
+          cards:
+            - { type: cardMedia, title: Living, entities: [ { entity: media_player.living } ] }
+    "#;
+    const PLAYER: &str = "media_player.living";
+
+    fn store(state: &str, attributes: Value) -> StateStore {
+        let player = EntityState {
+            state: state.to_string(),
+            attributes: attributes.as_object().cloned().unwrap_or_default(),
+            last_changed: 0.0,
+        };
+        let store = StateStore::new();
+        store.apply_states("panel", BTreeMap::from([(PLAYER.to_string(), player)]));
+        store
+    }
+
+    fn media_items(store: &StateStore) -> Vec<String> {
+        let config = Config::from_yaml(DEVICES);
+        match Media::get_media(&config, &config.devices["panel"], &store.get("panel")) {
+            Some(PanelMessage::EntityUpd { heading, items, .. }) => {
+                assert_eq!(heading, "Living");
+                items
+            }
+            other => panic!("Unexpected media update {:?}", other),
+        }
+    }
+
+    #[test]
+    fn buttons_follow_supported_features() {
+        let attributes = json!({
+            "media_title": "Song",
+            "media_artist": "Artist",
+            "volume_level": 0.45,
+            "shuffle": true,
+            "source": "Radio",
+            "source_list": ["Radio", "TV"],
+        });
+        let mut all = attributes.clone();
+        all["supported_features"] =
+            json!(SUPPORT_TURN_ON | SUPPORT_TURN_OFF | SUPPORT_SELECT_SOURCE | SUPPORT_SHUFFLE_SET);
+
+        let items = media_items(&store("playing", all));
+        assert_eq!(
+            items[..6],
+            [PLAYER, "Song", "", "Artist", "", "45"].map(str::to_string)
+        );
+        assert_eq!(items[7], COLOR_ON.to_string());
+        assert_ne!(items[8], DISABLED);
+        assert_eq!(
+            items[9..],
+            [
+                "media_pl",
+                PLAYER,
+                "\0",
+                &COLOR_OFF.to_string(),
+                "Radio",
+                "Radio"
+            ]
+            .map(str::to_string)
+        );
+
+        // Without any feature the on/off and shuffle buttons are hidden, no source row
+        let items = media_items(&store("off", attributes));
+        assert_eq!(items[7], DISABLED);
+        assert_eq!(items[8], DISABLED);
+        assert_eq!(items.len(), 9);
+    }
+
+    #[test]
+    fn power_button_color() {
+        let features = json!({"supported_features": SUPPORT_TURN_ON | SUPPORT_TURN_OFF});
+        assert_eq!(
+            media_items(&store("off", features.clone()))[7],
+            COLOR_OFF.to_string()
+        );
+        assert_eq!(
+            media_items(&store("idle", features))[7],
+            COLOR_ON.to_string()
+        );
+    }
+
+    #[test]
+    fn service_call() {
+        let store = store(
+            "playing",
+            json!({"shuffle": true, "source_list": ["Radio", "TV"]}),
+        );
+        let call =
+            |action: &str, value: &str| Media::service_call(&store, "panel", PLAYER, action, value);
+
+        for (action, service) in [
+            ("media-back", "media_previous_track"),
+            ("media-pause", "media_play_pause"),
+            ("media-next", "media_next_track"),
+            ("media-OnOff", "toggle"),
+        ] {
+            let service_call = call(action, "").unwrap();
+            assert_eq!(service_call.domain, "media_player");
+            assert_eq!(service_call.service, service);
+            assert_eq!(service_call.service_data["entity_id"], json!(PLAYER));
+        }
+
+        let shuffle = call("media-shuffle", "").unwrap();
+        assert_eq!(shuffle.service, "shuffle_set");
+        assert_eq!(shuffle.service_data["shuffle"], json!(false));
+
+        let volume = call("volumeSlider", "45").unwrap();
+        assert_eq!(volume.service, "volume_set");
+        assert_eq!(volume.service_data["volume_level"], json!(0.45));
+
+        let source = call("mode-media_pl", "1").unwrap();
+        assert_eq!(source.service, "select_source");
+        assert_eq!(source.service_data["source"], json!("TV"));
+
+        assert!(call("mode-media_pl", "2").is_none());
+        assert!(call("volumeSlider", "loud").is_none());
+        assert!(call("media-stop", "").is_none());
+    }
+}
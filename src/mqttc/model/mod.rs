@@ -1,2 +1,14 @@
 pub(crate) mod alarm;
+pub(crate) mod brightness;
+pub(crate) mod chart;
+pub(crate) mod cover;
+pub(crate) mod entities;
+pub(crate) mod fan;
+pub(crate) mod grid;
+pub(crate) mod light;
+pub(crate) mod media;
+pub(crate) mod power;
 pub(crate) mod screensaver;
+pub(crate) mod select;
+pub(crate) mod thermo;
+pub(crate) mod unlock;
@@ -0,0 +1,214 @@
+use crate::cards::Card;
+use crate::config::schema::{Config, Device, Entity};
+use crate::homeassitant::entities::EntityState;
+use crate::mqttc::model::grid::{COLOR_OFF, COLOR_UNAVAILABLE};
+use crate::protocol::{PanelMessage, NAVIGATION};
+use crate::state::{DeviceState, StateStore};
+use std::collections::BTreeMap;
+
+/// Number of flows displayed around the home icon.
+const FLOW_SLOTS: usize = 6;
+/// Flow speed factor when the entity has no `speed`, 1 for each 100 W.
+const DEFAULT_SPEED: f64 = 0.01;
+/// The panel animation supports speeds from -10 to 10, 0 stops the flow.
+const MAX_SPEED: f64 = 10.0;
+const DEFAULT_ICON: &str = "flash";
+
+/// The Power card page (`cardPower`), an energy flow diagram. The first entity of the card is
+/// displayed in the center (home), the next 6 ones are the flows around it.
+pub struct Power {}
+
+impl Power {
+    /// Process the power sensors and pass back the result into the insert_message function.
+    /// For more details look on `Power::get_power()` function.
+    pub fn process_power_data<F>(
+        config: &Config,
+        store: &StateStore,
+        device: &Device,
+        entities: &BTreeMap<String, EntityState>,
+        mut insert_message: F,
+    ) where
+        F: FnMut(Card, Vec<PanelMessage>),
+    {
+        let Some(card) = config.get_card_by_name(&device.id, Card::CardPower.as_str()) else {
+            return;
+        };
+        if !card
+            .entities
+            .iter()
+            .any(|e| entities.contains_key(&e.entity))
+        {
+            return;
+        }
+        if let Some(update) = Power::get_power(config, device, &store.get(&device.id)) {
+            insert_message(Card::CardPower, vec![update]);
+        }
+    }
+
+    /// Build the power page update.
+    /// * Message format, the block is sent for the home and repeated for each of the 6 flows.
+    /// ```
+    /// entityUpd~{title}~1|1~{type}~{entity}~{icon}~{color}~{name}~{value}~{speed}~...
+    /// ```
+    pub fn get_power(
+        config: &Config,
+        device: &Device,
+        device_state: &DeviceState,
+    ) -> Option<PanelMessage> {
+        let card = config.get_card_by_name(&device.id, Card::CardPower.as_str())?;
+        let items: Vec<String> = (0..=FLOW_SLOTS)
+            .flat_map(|i| match card.entities.get(i) {
+                Some(entity) => Power::get_flow(config, entity, device_state),
+                None => Default::default(),
+            })
+            .collect();
+
+        Some(PanelMessage::EntityUpd {
+            heading: card.title.unwrap_or_default(),
+            navigation: NAVIGATION.to_string(),
+            items,
+        })
+    }
+
+    fn get_flow(config: &Config, entity: &Entity, device_state: &DeviceState) -> [String; 7] {
+        let stored = device_state.entities.get(&entity.entity);
+        let value = stored.and_then(|s| s.state.parse::<f64>().ok());
+        let text = match (stored, value) {
+            (Some(stored), Some(_)) => match stored.attribute_str("unit_of_measurement") {
+                Some(unit) => format!("{} {}", stored.state, unit),
+                None => stored.state.clone(),
+            },
+            _ => String::default(),
+        };
+        let speed = value.map_or(0.0, |value| {
+            (value * entity.speed.unwrap_or(DEFAULT_SPEED))
+                .round()
+                .clamp(-MAX_SPEED, MAX_SPEED)
+        });
+        let color = match stored {
+            Some(stored) if stored.is_available() => entity.color.unwrap_or(COLOR_OFF),
+            _ => COLOR_UNAVAILABLE,
+        };
+        let icon = entity.icon.as_deref().unwrap_or(DEFAULT_ICON);
+        let name = entity
+            .name
+            .clone()
+            .or_else(|| stored.and_then(|s| s.friendly_name().map(str::to_string)))
+            .unwrap_or_default();
+
+        [
+            "text".to_string(),
+            entity.entity.clone(),
+            config
+                .icons
+                .get(icon.trim_start_matches("mdi:"))
+                .map_or('\0', |&c| c)
+                .to_string(),
+            color.to_string(),
+            name,
+            text,
+            (speed as i8).to_string(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const DEVICES: &str = r#"
+        panel:
+          module: nspanel
+          id: panel
+          mqtt: { rx_topic: cmnd/panel/CustomSend, tx_topic: tele/panel/RESULT }
+          model: EU
+          config: { timeout_to_screensaver: 20, screensaver_brightness: [], locale: en_US,
+                    timezone: Europe/Bucharest }
+          cards:
+            - type: cardPower
+              title: Energy
+              entities:
+                - { entity: sensor.home, name: Home }
+                - { entity: sensor.solar, name: Solar }
+                - { entity: sensor.grid, name: Grid, speed: -0.02 }
+                - { entity: sensor.battery, name: Battery, speed: 0.5 }
+    "#;
+
+    fn device_state(states: &[(&str, &str)]) -> DeviceState {
+        let entities = states.iter().map(|(entity, state)| {
+            let stored = EntityState {
+                state: state.to_string(),
+                attributes: json!({"unit_of_measurement": "W"})
+                    .as_object()
+                    .cloned()
+                    .unwrap_or_default(),
+                last_changed: 0.0,
+            };
+            (entity.to_string(), stored)
+        });
+        DeviceState {
+            entities: entities.collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The `{value}` and `{speed}` of each block of the page.
+    fn flows(device_state: &DeviceState) -> Vec<(String, String)> {
+        let config = Config::from_yaml(DEVICES);
+        let Some(PanelMessage::EntityUpd { items, .. }) =
+            Power::get_power(&config, &config.devices["panel"], device_state)
+        else {
+            panic!("Missing power update");
+        };
+        assert_eq!(items.len(), (FLOW_SLOTS + 1) * 7);
+        items
+            .chunks(7)
+            .take(4)
+            .map(|block| (block[5].clone(), block[6].clone()))
+            .collect()
+    }
+
+    fn flow(value: &str, speed: &str) -> (String, String) {
+        (value.to_string(), speed.to_string())
+    }
+
+    #[test]
+    fn flow_direction_follows_the_speed_factor() {
+        let flows = flows(&device_state(&[
+            ("sensor.home", "450"),
+            ("sensor.solar", "240"),
+            ("sensor.grid", "210"),
+            ("sensor.battery", "-6"),
+        ]));
+        assert_eq!(
+            flows,
+            [
+                flow("450 W", "5"),
+                flow("240 W", "2"),
+                // A negative factor reverses the flow
+                flow("210 W", "-4"),
+                flow("-6 W", "-3"),
+            ]
+        );
+    }
+
+    #[test]
+    fn flow_speed_is_clamped() {
+        let flows = flows(&device_state(&[
+            ("sensor.home", "5000"),
+            ("sensor.solar", "-2500"),
+            ("sensor.grid", "-900"),
+            ("sensor.battery", "unavailable"),
+        ]));
+        assert_eq!(
+            flows,
+            [
+                flow("5000 W", "10"),
+                flow("-2500 W", "-10"),
+                flow("-900 W", "10"),
+                flow("", "0"),
+            ]
+        );
+    }
+}
@@ -1,8 +1,13 @@
 use crate::cards::Card;
-use crate::config::schema::{Config, Device, Entity};
-use crate::homeassitant::events::RootEvent;
-use chrono::NaiveDateTime;
+use crate::config::schema::{Config, Device};
+use crate::homeassitant::entities::EntityState;
+use crate::protocol::PanelMessage;
+use crate::state::{DeviceState, StateStore};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use log::error;
 use serde_json::Value;
+use std::collections::BTreeMap;
 
 /// The Screensaver card page.
 /// This is responsible for transforming data into mqtt message that can be translated by
@@ -14,17 +19,23 @@ impl Screensaver {
     /// For more details look on `Screensaver::get_room_temperature()` function.
     pub fn process_temperature_sensor<F>(
         config: &Config,
-        value: &str,
+        store: &StateStore,
         device: &Device,
+        entities: &BTreeMap<String, EntityState>,
         mut insert_message: F,
     ) where
-        F: FnMut(Card, Vec<String>),
+        F: FnMut(Card, Vec<PanelMessage>),
     {
-        if let Some(temp_sensor) = device.get_entity_by_name(&"temperatureSensor") {
-            insert_message(
-                Card::Screensaver,
-                Screensaver::get_room_temperature(config, value, temp_sensor, &device.id),
-            );
+        if let Some(temp_sensor) = device.get_entity_by_name("temperatureSensor") {
+            if let Some(state) = entities
+                .get(&temp_sensor.entity)
+                .filter(|s| s.is_available())
+            {
+                insert_message(
+                    Card::Screensaver,
+                    Screensaver::get_room_temperature(config, store, state, &device.id),
+                );
+            }
         }
     }
 
@@ -32,65 +43,86 @@ impl Screensaver {
     /// For more details look on `Screensaver::get_weather_and_colors()` function.
     pub fn process_weather<F>(
         config: &Config,
+        store: &StateStore,
         device: &Device,
-        value: &str,
-        json: &RootEvent,
+        entities: &BTreeMap<String, EntityState>,
         mut insert_message: F,
     ) where
-        F: FnMut(Card, Vec<String>),
+        F: FnMut(Card, Vec<PanelMessage>),
     {
-        // Weather
-        if let Some(weather) = device.get_entity_by_name(&"weather") {
-            if let Some(v) = json.event.entities.get(&*weather.entity) {
-                // Removing cases when weather is disabled/unavailable. Unable to map to existing event struct
-                if !v.to_string().contains(r#""a":{"restored":true"#)
-                    && !v.to_string().contains(r#"s":"unavailable"#)
-                {
-                    insert_message(
-                        Card::Screensaver,
-                        Screensaver::get_weather_and_colors(&config, value, v, weather),
-                    );
-                }
+        if let Some(weather) = device.get_entity_by_name("weather") {
+            // Removing cases when weather is disabled/unavailable.
+            if let Some(state) = entities.get(&weather.entity).filter(|s| s.is_available()) {
+                insert_message(
+                    Card::Screensaver,
+                    Screensaver::get_weather_and_colors(config, store, &device.id, state),
+                );
             }
         }
     }
 
+    /// Current time in the device timezone.
+    /// * Message format, `{am_pm}` is empty when the 24-hour clock is used
+    /// ```
+    /// time~{time}~{am_pm}
+    /// ```
+    pub fn get_time(device: &Device) -> PanelMessage {
+        let dt = Utc::now().with_timezone(&device.timezone());
+        if device.config.time_12h {
+            PanelMessage::Time {
+                time: dt.format("%-I:%M").to_string(),
+                am_pm: dt.format_localized("%p", device.locale()).to_string(),
+            }
+        } else {
+            PanelMessage::Time {
+                time: dt.format("%H:%M").to_string(),
+                am_pm: String::default(),
+            }
+        }
+    }
+
+    /// Current date in the device timezone, formatted with `Device::date_format()`.
+    /// * Message format
+    /// ```
+    /// date~{date}
+    /// ```
+    pub fn get_date(device: &Device) -> PanelMessage {
+        Screensaver::date_at(device, Utc::now().with_timezone(&device.timezone()))
+    }
+
+    /// The date message of `dt`, see `Screensaver::get_date()`.
+    fn date_at(device: &Device, dt: DateTime<Tz>) -> PanelMessage {
+        PanelMessage::Date(
+            dt.format_localized(&device.date_format(), device.locale())
+                .to_string(),
+        )
+    }
+
     /// Extract the sensor temperature value and returning a vector that has a specific message format.
     /// * Message format
     /// ```
-    /// temperature~{}~{}°C
+    /// temperature~{icon}~{temp}°C
     /// ```
     fn get_room_temperature(
         config: &Config,
-        value: &str,
-        temp_sensor: Entity,
+        store: &StateStore,
+        temp_sensor: &EntityState,
         device_id: &str,
-    ) -> Vec<String> {
-        use crate::utils::DeviceState;
-        use regex::Regex;
+    ) -> Vec<PanelMessage> {
+        let device_state = DeviceState {
+            temp: Some(temp_sensor.state.clone()),
+            ..Default::default()
+        };
+        store.update(device_id, device_state);
 
-        let regex = format!(
-            r#"\B"{}":\{{["\+":\{{]*"s":"(\d{{2}}\.\d)(.*?)"\B"#,
-            temp_sensor.entity
-        );
-        let rgx = Regex::new(regex.as_str()).unwrap();
-        if let Ok(caps) = rgx.captures(&*value).ok_or("no match") {
-            let temp = caps.get(1).map_or("", |m| m.as_str());
-
-            let mut device_state = DeviceState::default();
-            device_state.temp = Some(temp.to_string());
-            DeviceState::read_process_overwrite(device_id, device_state);
-
-            return vec![format!(
-                "temperature~{}~{}°C",
-                config
-                    .icons
-                    .get("home-thermometer-outline")
-                    .map_or('\0', |&c| c),
-                temp
-            )];
-        }
-        Vec::default()
+        vec![PanelMessage::Temperature {
+            icon: config
+                .icons
+                .get("home-thermometer-outline")
+                .map_or('\0', |&c| c)
+                .to_string(),
+            text: format!("{}°C", temp_sensor.state),
+        }]
     }
 
     /// Extract the weather value and returning a vector that has a specific message format.
@@ -108,48 +140,34 @@ impl Screensaver {
     ///
     fn get_weather_and_colors(
         config: &Config,
-        value: &str,
-        v: &Value,
-        weather_entity: Entity,
-    ) -> Vec<String> {
-        use crate::homeassitant::events::{Weather, WeatherEvent, WeatherForecast};
-        use crate::utils::{
-            get_screensaver_color_output, get_weather_icon, STORED_STATE, WEATHER_COLORS_KEY,
-            WEATHER_KEY,
-        };
+        store: &StateStore,
+        device_id: &str,
+        weather: &EntityState,
+    ) -> Vec<PanelMessage> {
+        use crate::homeassitant::events::{WeatherEventData, WeatherForecast};
+        use crate::utils::{get_screensaver_color_output, get_weather_icon};
         use chrono::Datelike;
         use std::collections::HashMap;
 
-        let weather;
-        if value.contains(format!(r#"{}":{{"s"#, weather_entity.entity).as_str())
-            && !value.contains(r#"s":"unknown"#)
-        {
-            let w: WeatherEvent = serde_json::from_value(v.clone())
-                .expect("Failed to convert to WeatherEvent struct");
-            weather = w;
-        } else {
-            let w: Weather =
-                serde_json::from_value(v.clone()).expect("Failed to convert to Weather struct");
-            weather = w.event;
-        }
-        let mut weather_color = String::default();
-        let mut weather_update = String::default();
-        if weather.data.is_some() && weather.data.clone().unwrap().forecast.len() >= 4 {
-            let data = weather.data.unwrap();
+        let data: Option<WeatherEventData> =
+            serde_json::from_value(Value::Object(weather.attributes.clone()))
+                .map_err(|e| error!("Unable to read the weather attributes {:?}", e))
+                .ok();
+        let mut weather_color = None;
+        let mut weather_update = None;
+        if let Some(data) = data.filter(|data| data.forecast.len() >= 4) {
             // Extracting forecast_icons. Eg: Cloudy, Sunny, etc
-            let forecast_icons: HashMap<String, String> = std::iter::once((
-                "tMainIcon".to_string(),
-                weather.state.clone().unwrap_or_default(),
-            ))
-            .chain(data.forecast.iter().enumerate().map(|(i, f)| {
-                (
-                    format!("tF{}Icon", i + 1),
-                    f.condition.clone().unwrap_or_default(),
-                )
-            }))
-            .collect();
-
-            weather_color = get_screensaver_color_output(forecast_icons);
+            let forecast_icons: HashMap<String, String> =
+                std::iter::once(("tMainIcon".to_string(), weather.state.clone()))
+                    .chain(data.forecast.iter().enumerate().map(|(i, f)| {
+                        (
+                            format!("tF{}Icon", i + 1),
+                            f.condition.clone().unwrap_or_default(),
+                        )
+                    }))
+                    .collect();
+
+            weather_color = Some(get_screensaver_color_output(forecast_icons));
 
             let extract_weekday = |datetime_str: &str| -> chrono::Weekday {
                 NaiveDateTime::parse_from_str(datetime_str, "%Y-%m-%dT%H:%M:%S")
@@ -162,41 +180,100 @@ impl Screensaver {
 
             let icons = &config.icons;
             let format_forecast = |forecast: &WeatherForecast| {
-                format!(
-                    "{}~{}~{:.1}°C~{:.1}°C",
-                    extract_weekday(&forecast.datetime.clone().unwrap_or_default()),
-                    get_weather_icon(forecast.condition.clone().unwrap_or_default(), icons),
-                    forecast.temperature.unwrap_or(-99.9),
-                    forecast.templow.unwrap_or(-99.9),
-                )
+                vec![
+                    extract_weekday(&forecast.datetime.clone().unwrap_or_default()).to_string(),
+                    get_weather_icon(forecast.condition.clone().unwrap_or_default(), icons)
+                        .to_string(),
+                    format!("{:.1}°C", forecast.temperature.unwrap_or(-99.9)),
+                    format!("{:.1}°C", forecast.templow.unwrap_or(-99.9)),
+                ]
             };
 
             let weather_icon = |condition: &str| get_weather_icon(condition.to_string(), icons);
-            weather_update = format!(
-                "weatherUpdate~{}~{:.1}°C~{}~{}~{}~{}",
-                weather_icon(&weather.state.unwrap_or_default()),
-                data.temperature.unwrap_or(-99.9),
-                format_forecast(&data.forecast[0]),
-                format_forecast(&data.forecast[1]),
-                format_forecast(&data.forecast[2]),
-                format_forecast(&data.forecast[3]),
-            );
-        }
-        {
-            let mut map = STORED_STATE
-                .write()
-                .expect("Failed to acquire write lock on STORED_STATE: Lock is poisoned!");
-            map.insert(WEATHER_KEY.to_string(), weather_update.clone());
-            map.insert(WEATHER_COLORS_KEY.to_string(), weather_color.clone());
+            weather_update = Some(PanelMessage::WeatherUpdate(
+                [
+                    vec![
+                        weather_icon(&weather.state).to_string(),
+                        format!("{:.1}°C", data.temperature.unwrap_or(-99.9)),
+                    ],
+                    format_forecast(&data.forecast[0]),
+                    format_forecast(&data.forecast[1]),
+                    format_forecast(&data.forecast[2]),
+                    format_forecast(&data.forecast[3]),
+                ]
+                .concat(),
+            ));
         }
-        let mut result = vec![];
-        if !weather_update.is_empty() {
-            result.push(weather_update);
-        }
-        if !weather_color.is_empty() {
-            // make sure the weather_color is always after weather_update, otherwise colors will not work
-            result.push(weather_color);
-        }
-        result
+        // make sure the weather_color is always after weather_update, otherwise colors will not work
+        let weather: Vec<PanelMessage> = weather_update.into_iter().chain(weather_color).collect();
+        let device_state = DeviceState {
+            weather: weather.clone(),
+            ..Default::default()
+        };
+        store.update(device_id, device_state);
+        weather
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn device_config(locale: &str, date_format: &str) -> Config {
+        Config::from_yaml(&format!(
+            r#"
+            panel:
+              module: nspanel
+              id: panel
+              mqtt: {{ rx_topic: cmnd/panel/CustomSend, tx_topic: tele/panel/RESULT }}
+              model: EU
+              config:
+                timeout_to_screensaver: 20
+                screensaver_brightness: []
+                locale: {}
+                timezone: Europe/Bucharest
+                date_format: "{}"
+              cards: []
+            "#,
+            locale, date_format
+        ))
+    }
+
+    fn date(config: &Config) -> String {
+        let device = &config.devices["panel"];
+        let dt = device
+            .timezone()
+            .with_ymd_and_hms(2024, 3, 15, 10, 30, 0)
+            .unwrap();
+        Screensaver::date_at(device, dt).to_string()
+    }
+
+    #[test]
+    fn date_in_device_locale() {
+        assert_eq!(
+            date(&device_config("en_US", "%A, %d. %B %Y")),
+            "date~Friday, 15. March 2024"
+        );
+        assert_eq!(
+            date(&device_config("de_DE", "%A, %d. %B %Y")),
+            "date~Freitag, 15. März 2024"
+        );
+    }
+
+    #[test]
+    fn invalid_date_format_falls_back_to_default() {
+        assert_eq!(
+            date(&device_config("en_US", "%d %Q %Y")),
+            "date~Friday, 15. March 2024"
+        );
+    }
+
+    #[test]
+    fn unsupported_locale_is_english() {
+        assert_eq!(
+            date(&device_config("xx_XX", "%d %B %Y")),
+            "date~15 March 2024"
+        );
     }
 }
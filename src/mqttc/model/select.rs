@@ -0,0 +1,125 @@
+use crate::homeassitant::commands::ServiceCall;
+use crate::homeassitant::entities::EntityState;
+use crate::mqttc::model::grid::{COLOR_OFF, COLOR_UNAVAILABLE};
+use crate::protocol::PanelMessage;
+use crate::state::StateStore;
+use serde_json::Value;
+
+/// The option list popup (`popupInSel`) of the `input_select` and `select` entities.
+pub struct Select {}
+
+impl Select {
+    /// Build the `popupInSel` option list, the list is named after the entity domain.
+    /// * Message format
+    /// ```
+    /// entityUpdateDetail2~{entity}~~{color}~{domain}~{option}~{option?option}~
+    /// ```
+    pub fn get_select_detail(entity: &str, select: &EntityState) -> PanelMessage {
+        let domain = entity.split('.').next().unwrap_or_default();
+        let color = match select.state.as_str() {
+            "unavailable" => COLOR_UNAVAILABLE,
+            _ => COLOR_OFF,
+        };
+        PanelMessage::EntityUpdateDetail2 {
+            entity: entity.to_string(),
+            items: vec![
+                String::default(),
+                color.to_string(),
+                domain.to_string(),
+                select.state.clone(),
+                Select::options(select).join("?"),
+                String::default(),
+            ],
+        }
+    }
+
+    /// Translate `mode-{domain},2` into `{domain}.select_option` using the option at index 2.
+    pub fn service_call(
+        store: &StateStore,
+        device_id: &str,
+        entity: &str,
+        action: &str,
+        value: &str,
+    ) -> Option<ServiceCall> {
+        let domain = entity.split('.').next()?;
+        if action.strip_prefix("mode-") != Some(domain) {
+            return None;
+        }
+        let index: usize = value.parse().ok()?;
+        let select = store.get(device_id).entities.get(entity)?.clone();
+        let option = Select::options(&select).get(index)?.clone();
+        Some(ServiceCall::new(domain, "select_option", entity).with("option", option))
+    }
+
+    fn options(select: &EntityState) -> Vec<String> {
+        select
+            .attributes
+            .get("options")
+            .and_then(Value::as_array)
+            .map(|options| {
+                options
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    fn store(entity: &str, state: &str) -> StateStore {
+        let select = EntityState {
+            state: state.to_string(),
+            attributes: json!({"options": ["Home", "Away", "Night"]})
+                .as_object()
+                .cloned()
+                .unwrap_or_default(),
+            last_changed: 0.0,
+        };
+        let store = StateStore::new();
+        store.apply_states("panel", BTreeMap::from([(entity.to_string(), select)]));
+        store
+    }
+
+    #[test]
+    fn detail_lists_options() {
+        let store = store("input_select.mode", "Away");
+        let select = &store.get("panel").entities["input_select.mode"];
+        assert_eq!(
+            Select::get_select_detail("input_select.mode", select).to_string(),
+            format!(
+                "entityUpdateDetail2~input_select.mode~~{}~input_select~Away~Home?Away?Night~",
+                COLOR_OFF
+            )
+        );
+    }
+
+    #[test]
+    fn service_call_select_option() {
+        for entity in ["input_select.mode", "select.mode"] {
+            let store = store(entity, "Home");
+            let domain = entity.split('.').next().unwrap();
+            let action = format!("mode-{}", domain);
+            let service = Select::service_call(&store, "panel", entity, &action, "2").unwrap();
+            assert_eq!(service.domain, domain);
+            assert_eq!(service.service, "select_option");
+            assert_eq!(service.service_data["entity_id"], json!(entity));
+            assert_eq!(service.service_data["option"], json!("Night"));
+
+            assert!(Select::service_call(&store, "panel", entity, &action, "3").is_none());
+        }
+
+        // The list is named after the entity domain
+        let store = store("select.mode", "Home");
+        assert!(
+            Select::service_call(&store, "panel", "select.mode", "mode-input_select", "0")
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,437 @@
+use crate::cards::Card;
+use crate::config::schema::{Config, Device};
+use crate::homeassitant::commands::ServiceCall;
+use crate::homeassitant::entities::EntityState;
+use crate::protocol::{PanelMessage, NAVIGATION};
+use crate::state::StateStore;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// Hvac mode -> (icon, color when the mode is active)
+const HVAC_MODES: [(&str, &str, u32); 7] = [
+    ("auto", "calendar-sync", 1024),
+    ("heat_cool", "calendar-sync", 1024),
+    ("heat", "fire", 64512),
+    ("off", "power", 35921),
+    ("cool", "snowflake", 11487),
+    ("dry", "water-percent", 60897),
+    ("fan_only", "fan", 35921),
+];
+/// Number of hvac mode buttons available on the thermostat page.
+const HVAC_SLOTS: usize = 8;
+/// Attributes that are displayed on the `popupThermo` detail page.
+const DETAIL_MODES: [(&str, &str); 3] = [
+    ("preset_modes", "Preset"),
+    ("swing_modes", "Swing mode"),
+    ("fan_modes", "Fan mode"),
+];
+const TEMPERATURE_UNIT: &str = "°C";
+
+/// The Thermostat card page.
+/// This is responsible for transforming climate entity data into mqtt messages that can be
+/// translated by Nspanel display and translating the thermostat buttons into Hass service calls.
+pub struct Thermo {}
+
+impl Thermo {
+    /// Process the climate data and pass back the result into the insert_message function
+    /// For more details look on `Thermo::get_thermo()` function.
+    pub fn process_climate_data<F>(
+        config: &Config,
+        device: &Device,
+        entities: &BTreeMap<String, EntityState>,
+        mut insert_message: F,
+    ) where
+        F: FnMut(Card, Vec<PanelMessage>),
+    {
+        if let Some(climate) = Thermo::get_climate_entity(config, &device.id) {
+            if let Some(thermo) = entities.get(&climate) {
+                insert_message(
+                    Card::CardThermo,
+                    vec![Thermo::get_thermo(config, device, &climate, thermo)],
+                );
+            }
+        }
+    }
+
+    /// The climate entity is the first entity of `cardThermo`.
+    pub fn get_climate_entity(config: &Config, device_id: &str) -> Option<String> {
+        config
+            .get_card_by_name(device_id, Card::CardThermo.as_str())
+            .and_then(|card| card.entities.into_iter().next())
+            .map(|entity| entity.entity)
+    }
+
+    /// Build the thermostat page update.
+    /// * Message format, hvac mode block `{icon}~{color}~{active}~{mode}` is repeated for
+    ///   each of the 8 buttons. Temperatures are sent multiplied by 10.
+    /// ```
+    /// entityUpd~{title}~1|1~{entity}~{current} °C~{target}~{status}~{min}~{max}~{step}
+    /// ~{icon}~{color}~{active}~{mode}...~Currently~State~Action~°C~{target_low}~{detail}
+    /// ```
+    pub fn get_thermo(
+        config: &Config,
+        device: &Device,
+        entity: &str,
+        thermo: &EntityState,
+    ) -> PanelMessage {
+        let attributes = &thermo.attributes;
+        let title = config
+            .get_card_by_name(&device.id, Card::CardThermo.as_str())
+            .and_then(|card| card.title)
+            .or_else(|| {
+                attributes
+                    .get("friendly_name")
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+            })
+            .unwrap_or_default();
+
+        let current_temp = attributes
+            .get("current_temperature")
+            .and_then(Value::as_f64)
+            .map(|t| format!("{} {}", t, TEMPERATURE_UNIT))
+            .unwrap_or_default();
+        // Single target thermostats are using `temperature`, the range ones are using high/low.
+        let (target, target_low) = match attributes.get("temperature").and_then(Value::as_f64) {
+            Some(temperature) => (Thermo::tenths(temperature), String::default()),
+            None => (
+                Thermo::tenths(Thermo::attribute_f64(attributes, "target_temp_high", 0.0)),
+                attributes
+                    .get("target_temp_low")
+                    .and_then(Value::as_f64)
+                    .map(|t| Thermo::tenths(t).to_string())
+                    .unwrap_or_default(),
+            ),
+        };
+        let status = format!(
+            "{}\r\n({})",
+            Thermo::humanize(
+                attributes
+                    .get("hvac_action")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+            ),
+            Thermo::humanize(&thermo.state)
+        );
+
+        let mut hvac_modes: Vec<String> = attributes
+            .get("hvac_modes")
+            .and_then(Value::as_array)
+            .map(|modes| {
+                modes
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .take(HVAC_SLOTS)
+                    .flat_map(|mode| {
+                        let (icon, color) = HVAC_MODES
+                            .iter()
+                            .find(|(m, _, _)| *m == mode)
+                            .map_or(("thermometer", 64512), |(_, icon, color)| (*icon, *color));
+                        [
+                            config.icons.get(icon).map_or('\0', |&c| c).to_string(),
+                            color.to_string(),
+                            if mode == thermo.state { "1" } else { "0" }.to_string(),
+                            mode.to_string(),
+                        ]
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        // Unused buttons are sent empty
+        hvac_modes.resize(HVAC_SLOTS * 4, String::default());
+
+        let has_detail = DETAIL_MODES
+            .iter()
+            .any(|(mode, _)| attributes.contains_key(*mode));
+
+        let items = [
+            vec![
+                entity.to_string(),
+                current_temp,
+                target.to_string(),
+                status,
+                Thermo::tenths(Thermo::attribute_f64(attributes, "min_temp", 7.0)).to_string(),
+                Thermo::tenths(Thermo::attribute_f64(attributes, "max_temp", 35.0)).to_string(),
+                Thermo::tenths(Thermo::attribute_f64(attributes, "target_temp_step", 0.5))
+                    .to_string(),
+            ],
+            hvac_modes,
+            vec![
+                "Currently".to_string(),
+                "State".to_string(),
+                "Action".to_string(),
+                TEMPERATURE_UNIT.to_string(),
+                target_low,
+                // 0 enables the detail button, 1 hides it
+                if has_detail { "0" } else { "1" }.to_string(),
+            ],
+        ]
+        .concat();
+        PanelMessage::EntityUpd {
+            heading: title,
+            navigation: NAVIGATION.to_string(),
+            items,
+        }
+    }
+
+    /// Build the `popupThermo` detail page with preset, swing and fan modes.
+    /// * Message format, the mode block is repeated for each mode supported by the entity.
+    /// ```
+    /// entityUpdateDetail~{entity}~{icon}~{color}~{heading}~{mode}~{current}~{option?option}~
+    /// ```
+    pub fn get_thermo_detail(config: &Config, entity: &str, thermo: &EntityState) -> PanelMessage {
+        let (icon, color) = HVAC_MODES
+            .iter()
+            .find(|(m, _, _)| *m == thermo.state)
+            .map_or(("thermometer", 64512), |(_, icon, color)| (*icon, *color));
+
+        let modes: Vec<String> = DETAIL_MODES
+            .iter()
+            .filter_map(|(mode, heading)| {
+                let options = thermo.attributes.get(*mode).and_then(Value::as_array)?;
+                let current = thermo
+                    .attributes
+                    .get(mode.trim_end_matches('s'))
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                let options = options
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .collect::<Vec<&str>>()
+                    .join("?");
+                Some([
+                    heading.to_string(),
+                    mode.to_string(),
+                    current.to_string(),
+                    options,
+                ])
+            })
+            .flatten()
+            .collect();
+
+        PanelMessage::EntityUpdateDetail {
+            entity: entity.to_string(),
+            items: [
+                vec![
+                    config.icons.get(icon).map_or('\0', |&c| c).to_string(),
+                    color.to_string(),
+                ],
+                modes,
+                vec![String::default()],
+            ]
+            .concat(),
+        }
+    }
+
+    /// Translate a thermostat button press into a Hass `climate` service call.
+    /// Supported actions:
+    /// * `tempUpd,215` -> `climate.set_temperature` with `temperature: 21.5`
+    /// * `tempUpdHighLow,250|190` -> `climate.set_temperature` with high and low targets
+    /// * `hvac_action,heat` -> `climate.set_hvac_mode`
+    /// * `mode-preset_modes,2` -> `climate.set_preset_mode` using the option at index 2
+    pub fn service_call(
+        store: &StateStore,
+        device_id: &str,
+        entity: &str,
+        action: &str,
+        value: &str,
+    ) -> Option<ServiceCall> {
+        let service = |service: &str| ServiceCall::new("climate", service, entity);
+        match action {
+            "tempUpd" => {
+                Some(service("set_temperature").with("temperature", Thermo::degrees(value)?))
+            }
+            "tempUpdHighLow" => {
+                let (high, low) = value.split_once('|')?;
+                Some(
+                    service("set_temperature")
+                        .with("target_temp_high", Thermo::degrees(high)?)
+                        .with("target_temp_low", Thermo::degrees(low)?),
+                )
+            }
+            "hvac_action" => Some(service("set_hvac_mode").with("hvac_mode", value)),
+            _ => {
+                let mode = action.strip_prefix("mode-")?;
+                let index: usize = value.parse().ok()?;
+                let option = store
+                    .get(device_id)
+                    .entities
+                    .get(entity)?
+                    .attributes
+                    .get(mode)?
+                    .get(index)?
+                    .clone();
+                let name = match mode {
+                    "preset_modes" => "set_preset_mode",
+                    "swing_modes" => "set_swing_mode",
+                    "fan_modes" => "set_fan_mode",
+                    _ => return None,
+                };
+                Some(service(name).with(mode.trim_end_matches('s'), option))
+            }
+        }
+    }
+
+    fn attribute_f64(attributes: &Map<String, Value>, key: &str, default: f64) -> f64 {
+        attributes
+            .get(key)
+            .and_then(Value::as_f64)
+            .unwrap_or(default)
+    }
+
+    /// The panel is working with temperatures multiplied by 10.
+    fn tenths(value: f64) -> i64 {
+        (value * 10.0).round() as i64
+    }
+
+    fn degrees(value: &str) -> Option<f64> {
+        value.parse::<f64>().ok().map(|v| v / 10.0)
+    }
+
+    /// `fan_only` -> `Fan only`
+    fn humanize(value: &str) -> String {
+        let value = value.replace('_', " ");
+        let mut chars = value.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => String::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const DEVICES: &str = r#"
+        panel:
+          module: nspanel
+          id: panel
+          mqtt: { rx_topic: cmnd/panel/CustomSend, tx_topic: tele/panel/RESULT }
+          model: EU
+          config: { timeout_to_screensaver: 20, screensaver_brightness: [], locale: en_US,
+                    timezone: Europe/Bucharest }
+          cards:
+            - { type: cardThermo, title: Bedroom, entities: [ { entity: climate.bedroom } ] }
+    "#;
+
+    fn thermo(state: &str, attributes: Value) -> EntityState {
+        EntityState {
+            state: state.to_string(),
+            attributes: attributes.as_object().cloned().unwrap_or_default(),
+            last_changed: 0.0,
+        }
+    }
+
+    #[test]
+    fn thermo_page() {
+        let config = Config::from_yaml(DEVICES);
+        let thermo = thermo(
+            "heat",
+            json!({
+                "current_temperature": 21.5,
+                "temperature": 22,
+                "hvac_action": "heating",
+                "hvac_modes": ["heat", "off", "fan_only"],
+                "preset_modes": ["eco", "comfort"],
+            }),
+        );
+        let page = Thermo::get_thermo(
+            &config,
+            &config.devices["panel"],
+            "climate.bedroom",
+            &thermo,
+        )
+        .to_string();
+        let mut expected = [
+            "entityUpd~Bedroom~1|1~climate.bedroom~21.5 °C~220~Heating\r\n(Heat)~70~350~5",
+            "~\0~64512~1~heat",
+            "~\0~35921~0~off",
+            "~\0~35921~0~fan_only",
+        ]
+        .concat();
+        expected.push_str(&"~~~~".repeat(HVAC_SLOTS - 3));
+        expected.push_str("~Currently~State~Action~°C~~0");
+        assert_eq!(page, expected);
+    }
+
+    #[test]
+    fn thermo_page_with_range() {
+        let config = Config::from_yaml(DEVICES);
+        let thermo = thermo(
+            "heat_cool",
+            json!({"target_temp_high": 24, "target_temp_low": 19.5, "hvac_modes": ["heat_cool"]}),
+        );
+        let page = Thermo::get_thermo(
+            &config,
+            &config.devices["panel"],
+            "climate.bedroom",
+            &thermo,
+        )
+        .to_string();
+        // No current temperature yet, the detail button is hidden without modes
+        assert!(page.starts_with("entityUpd~Bedroom~1|1~climate.bedroom~~240~\r\n(Heat cool)~"));
+        assert!(page.contains("~\0~1024~1~heat_cool~"));
+        assert!(page.ends_with("~Currently~State~Action~°C~195~1"));
+    }
+
+    #[test]
+    fn service_call_temperatures() {
+        let service = Thermo::service_call(
+            &StateStore::new(),
+            "panel",
+            "climate.bedroom",
+            "tempUpd",
+            "215",
+        )
+        .unwrap();
+        assert_eq!(service.service, "set_temperature");
+        assert_eq!(service.service_data["temperature"], json!(21.5));
+
+        let service = Thermo::service_call(
+            &StateStore::new(),
+            "panel",
+            "climate.bedroom",
+            "tempUpdHighLow",
+            "240|195",
+        )
+        .unwrap();
+        assert_eq!(service.service, "set_temperature");
+        assert_eq!(service.service_data["target_temp_high"], json!(24.0));
+        assert_eq!(service.service_data["target_temp_low"], json!(19.5));
+
+        assert!(Thermo::service_call(
+            &StateStore::new(),
+            "panel",
+            "climate.bedroom",
+            "tempUpd",
+            "warm"
+        )
+        .is_none());
+        assert!(Thermo::service_call(
+            &StateStore::new(),
+            "panel",
+            "climate.bedroom",
+            "tempUpdHighLow",
+            "240"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn service_call_hvac_mode() {
+        let service = Thermo::service_call(
+            &StateStore::new(),
+            "panel",
+            "climate.bedroom",
+            "hvac_action",
+            "fan_only",
+        )
+        .unwrap();
+        assert_eq!(service.domain, "climate");
+        assert_eq!(service.service, "set_hvac_mode");
+        assert_eq!(service.service_data["hvac_mode"], json!("fan_only"));
+        assert_eq!(service.service_data["entity_id"], json!("climate.bedroom"));
+    }
+}
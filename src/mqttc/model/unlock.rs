@@ -0,0 +1,143 @@
+use crate::cards::Card;
+use crate::config::schema::{Config, Device};
+use crate::protocol::{PanelMessage, NAVIGATION};
+use crate::state::{DeviceState, StateStore};
+use chrono::Utc;
+use ring::digest::{digest, SHA256};
+use std::collections::BTreeMap;
+
+/// Prefix of a hashed pin, followed by the hex encoded SHA-256 digest of the code.
+const SHA256_PREFIX: &str = "sha256:";
+/// Action sent back by the keypad button.
+pub const UNLOCK_ACTION: &str = "cardUnlock-unlock";
+const COLOR_LOCKED: u32 = 55907;
+const COLOR_WRONG: u32 = 63488; // red
+
+/// The `cardUnlock` page, a pin keypad shown in front of locked cards. The panel has no
+/// dedicated page, the `cardAlarm` keypad is reused with a single `Unlock` button.
+pub struct Unlock {}
+
+impl Unlock {
+    /// The pin protecting the card, its own `pin` or the device `unlock_pin` when `locked`.
+    fn get_pin(config: &Config, device: &Device, card: &str) -> Option<String> {
+        let card = config.get_card_by_name(&device.id, card)?;
+        card.pin.or_else(|| {
+            card.locked
+                .then(|| device.config.unlock_pin.clone())
+                .flatten()
+        })
+    }
+
+    /// A card with a pin is locked until the pin is entered, for `unlock_timeout` seconds.
+    pub fn is_locked(config: &Config, store: &StateStore, device: &Device, card: &str) -> bool {
+        if Unlock::get_pin(config, device, card).is_none() {
+            return false;
+        }
+        store
+            .get(&device.id)
+            .unlocked
+            .get(card)
+            .is_none_or(|until| *until <= Utc::now().timestamp())
+    }
+
+    /// Check the entered code and remember the unlock of the card when it matches.
+    pub fn unlock(
+        config: &Config,
+        store: &StateStore,
+        device: &Device,
+        card: &str,
+        code: &str,
+    ) -> bool {
+        let Some(pin) = Unlock::get_pin(config, device, card) else {
+            return true;
+        };
+        if !Unlock::verify(&pin, code) {
+            return false;
+        }
+        let until = Utc::now().timestamp() + device.config.unlock_timeout as i64;
+        let device_state = DeviceState {
+            unlocked: BTreeMap::from([(card.to_string(), until)]),
+            ..Default::default()
+        };
+        store.update(&device.id, device_state);
+        true
+    }
+
+    /// Compare the code with a plain pin, or with the digest of a `sha256:` pin.
+    fn verify(pin: &str, code: &str) -> bool {
+        match pin.strip_prefix(SHA256_PREFIX) {
+            Some(hash) => {
+                let hex: String = digest(&SHA256, code.as_bytes())
+                    .as_ref()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+                hex.eq_ignore_ascii_case(hash.trim())
+            }
+            None => pin == code,
+        }
+    }
+
+    /// Build the keypad update for the locked card, using the `cardAlarm` layout.
+    /// * Message format
+    /// ```
+    /// entityUpd~{card title}~1|1~cardUnlock-{card}~Unlock~cardUnlock-unlock~~~~~~~{icon}~{color}~enable~disable~
+    /// ```
+    pub fn get_unlock(config: &Config, device: &Device, card: &str, wrong: bool) -> PanelMessage {
+        let title = config
+            .get_card_by_name(&device.id, card)
+            .and_then(|card| card.title)
+            .unwrap_or_default();
+        let (icon, color) = if wrong {
+            ("lock-alert", COLOR_WRONG)
+        } else {
+            ("lock", COLOR_LOCKED)
+        };
+        let mut items = vec![
+            format!("{}-{}", Card::CardUnlock.as_str(), card),
+            "Unlock".to_string(),
+            UNLOCK_ACTION.to_string(),
+        ];
+        // Unused alarm buttons are sent empty
+        items.resize(9, String::default());
+        items.extend([
+            config.icons.get(icon).map_or('\0', |&c| c).to_string(),
+            color.to_string(),
+            "enable".to_string(),
+            if wrong { "enable" } else { "disable" }.to_string(),
+            String::default(),
+        ]);
+        PanelMessage::EntityUpd {
+            heading: title,
+            navigation: NAVIGATION.to_string(),
+            items,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SHA-256 of `1234`
+    const HASH: &str = "03ac674216f3e15c761ee1a5e255f067953623c8b388b4459e13f978d7c846f4";
+
+    #[test]
+    fn verify_plain_pin() {
+        assert!(Unlock::verify("1234", "1234"));
+        assert!(!Unlock::verify("1234", "4321"));
+        assert!(!Unlock::verify("1234", ""));
+    }
+
+    #[test]
+    fn verify_hashed_pin() {
+        assert!(Unlock::verify(&format!("sha256:{}", HASH), "1234"));
+        assert!(Unlock::verify(
+            &format!("sha256:{}", HASH.to_uppercase()),
+            "1234"
+        ));
+        assert!(!Unlock::verify(&format!("sha256:{}", HASH), "4321"));
+        // The digest itself is not a valid code
+        assert!(!Unlock::verify(&format!("sha256:{}", HASH), HASH));
+    }
+}
@@ -0,0 +1,451 @@
+use bytes::Bytes;
+use serde_json::Value;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// Fields separator used by the NSPanel lovelace-ui firmware.
+const SEPARATOR: char = '~';
+/// Navigation field with both previous and next arrows visible.
+pub const NAVIGATION: &str = "1|1";
+
+/// Messages sent to the panel over `rx_topic`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PanelMessage {
+    /// `pageType~{page}`
+    PageType(String),
+    /// `entityUpd~{heading}~{navigation}~{items}...`
+    EntityUpd {
+        heading: String,
+        navigation: String,
+        items: Vec<String>,
+    },
+    /// `entityUpdateDetail~{entity}~{items}...`
+    EntityUpdateDetail { entity: String, items: Vec<String> },
+    /// `entityUpdateDetail2~{entity}~{items}...`, used by the `popupInSel` option list.
+    EntityUpdateDetail2 { entity: String, items: Vec<String> },
+    /// `weatherUpdate~{items}...`
+    WeatherUpdate(Vec<String>),
+    /// `color~{color}...`, for each position look at `utils.rs:DEFAULT_SCREENSAVER_COLOR_MAPPING`
+    Color(Vec<u32>),
+    /// `time~{time}~{am_pm}`
+    Time { time: String, am_pm: String },
+    /// `date~{date}`
+    Date(String),
+    /// `timeout~{seconds}`
+    Timeout(u16),
+    /// `dimmode~{dim}~{active}~{background}`, brightness is between 0 and 100.
+    DimMode {
+        dim: u16,
+        active: u16,
+        background: u32,
+    },
+    /// `notify~{heading}~{text}`
+    Notify { heading: String, text: String },
+    /// `temperature~{icon}~{text}`
+    Temperature { icon: String, text: String },
+    /// Anything else, sent as it is.
+    Raw(String),
+}
+
+/// Events received from the panel in the `CustomRecv` field of `tx_topic` payloads.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PanelEvent {
+    /// `event,startup,{version},{model}`
+    Startup { version: String, model: String },
+    /// `event,sleepReached,{page}`
+    SleepReached { page: String },
+    /// `event,buttonPress2,{entity},{action}[,{value}]`
+    /// `entity` is the page name for navigation buttons (`bNext`, `bPrev`, `bExit`).
+    ButtonPress2 {
+        entity: String,
+        action: String,
+        value: Option<String>,
+    },
+    /// `event,pageOpenDetail,{popup},{entity}`
+    PageOpenDetail { popup: String, entity: String },
+    /// Physical button, published by Tasmota on `stat/{topic}/RESULT` when the buttons are
+    /// detached from the relays (`SetOption73 1`): `{"Button1":{"Action":"SINGLE"}}`
+    Button { button: u8, action: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    /// Payload is not a json object with a `CustomRecv` string.
+    InvalidPayload(String),
+    /// Event is not known or it has missing fields.
+    UnknownEvent(String),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::InvalidPayload(payload) => write!(f, "Invalid payload: {}", payload),
+            ProtocolError::UnknownEvent(event) => write!(f, "Unknown event: {}", event),
+        }
+    }
+}
+
+/// The separator can't be escaped on the panel, so it is replaced from user text.
+pub fn escape(text: &str) -> String {
+    text.replace(SEPARATOR, "-")
+}
+
+impl PanelMessage {
+    fn fields(&self) -> Vec<String> {
+        let escaped = |items: &[String]| items.iter().map(|i| escape(i)).collect::<Vec<_>>();
+        match self {
+            PanelMessage::PageType(page) => vec!["pageType".into(), escape(page)],
+            PanelMessage::EntityUpd {
+                heading,
+                navigation,
+                items,
+            } => [
+                vec!["entityUpd".into(), escape(heading), escape(navigation)],
+                escaped(items),
+            ]
+            .concat(),
+            PanelMessage::EntityUpdateDetail { entity, items } => [
+                vec!["entityUpdateDetail".into(), escape(entity)],
+                escaped(items),
+            ]
+            .concat(),
+            PanelMessage::EntityUpdateDetail2 { entity, items } => [
+                vec!["entityUpdateDetail2".into(), escape(entity)],
+                escaped(items),
+            ]
+            .concat(),
+            PanelMessage::WeatherUpdate(items) => {
+                [vec!["weatherUpdate".into()], escaped(items)].concat()
+            }
+            PanelMessage::Color(colors) => std::iter::once("color".to_string())
+                .chain(colors.iter().map(u32::to_string))
+                .collect(),
+            PanelMessage::Time { time, am_pm } => {
+                vec!["time".into(), escape(time), escape(am_pm)]
+            }
+            PanelMessage::Date(date) => vec!["date".into(), escape(date)],
+            PanelMessage::Timeout(timeout) => vec!["timeout".into(), timeout.to_string()],
+            PanelMessage::DimMode {
+                dim,
+                active,
+                background,
+            } => vec![
+                "dimmode".into(),
+                dim.to_string(),
+                active.to_string(),
+                background.to_string(),
+            ],
+            PanelMessage::Notify { heading, text } => {
+                vec!["notify".into(), escape(heading), escape(text)]
+            }
+            PanelMessage::Temperature { icon, text } => {
+                vec!["temperature".into(), escape(icon), escape(text)]
+            }
+            PanelMessage::Raw(raw) => vec![raw.clone()],
+        }
+    }
+}
+
+impl Display for PanelMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.fields().join(&SEPARATOR.to_string()))
+    }
+}
+
+impl From<PanelMessage> for Bytes {
+    fn from(message: PanelMessage) -> Self {
+        Bytes::from(message.to_string())
+    }
+}
+
+impl FromStr for PanelMessage {
+    type Err = ProtocolError;
+
+    /// Decode a message sent to the panel, unknown messages are kept as `Raw`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<String> = s.split(SEPARATOR).map(str::to_string).collect();
+        let field = |i: usize| fields.get(i).cloned().unwrap_or_default();
+        let number = |i: usize| field(i).parse().ok();
+        let raw = || PanelMessage::Raw(s.to_string());
+
+        let message = match fields[0].as_str() {
+            "pageType" => PanelMessage::PageType(field(1)),
+            "entityUpd" if fields.len() >= 3 => PanelMessage::EntityUpd {
+                heading: field(1),
+                navigation: field(2),
+                items: fields[3..].to_vec(),
+            },
+            "entityUpdateDetail" if fields.len() >= 2 => PanelMessage::EntityUpdateDetail {
+                entity: field(1),
+                items: fields[2..].to_vec(),
+            },
+            "entityUpdateDetail2" if fields.len() >= 2 => PanelMessage::EntityUpdateDetail2 {
+                entity: field(1),
+                items: fields[2..].to_vec(),
+            },
+            "weatherUpdate" => PanelMessage::WeatherUpdate(fields[1..].to_vec()),
+            "color" => match fields[1..].iter().map(|c| c.parse().ok()).collect() {
+                Some(colors) => PanelMessage::Color(colors),
+                None => raw(),
+            },
+            "time" => PanelMessage::Time {
+                time: field(1),
+                am_pm: field(2),
+            },
+            "date" => PanelMessage::Date(field(1)),
+            "timeout" => number(1).map_or_else(raw, PanelMessage::Timeout),
+            "dimmode" => match (number(1), number(2), field(3).parse().ok()) {
+                (Some(dim), Some(active), Some(background)) => PanelMessage::DimMode {
+                    dim,
+                    active,
+                    background,
+                },
+                _ => raw(),
+            },
+            "notify" => PanelMessage::Notify {
+                heading: field(1),
+                text: field(2),
+            },
+            "temperature" => PanelMessage::Temperature {
+                icon: field(1),
+                text: field(2),
+            },
+            _ => raw(),
+        };
+        Ok(message)
+    }
+}
+
+impl PanelEvent {
+    /// Parse the mqtt payload published by the panel, eg: `{"CustomRecv":"event,startup,53,eu"}`
+    pub fn from_payload(payload: &str) -> Result<Self, ProtocolError> {
+        let data = serde_json::from_str::<Value>(payload).ok();
+        if let Some(button) = data.as_ref().and_then(PanelEvent::from_button) {
+            return Ok(button);
+        }
+        data.and_then(|data| data.get("CustomRecv")?.as_str().map(str::to_string))
+            .ok_or_else(|| ProtocolError::InvalidPayload(payload.to_string()))?
+            .parse()
+    }
+
+    fn from_button(data: &Value) -> Option<Self> {
+        data.as_object()?.iter().find_map(|(key, value)| {
+            Some(PanelEvent::Button {
+                button: key.strip_prefix("Button")?.parse().ok()?,
+                action: value.get("Action")?.as_str()?.to_string(),
+            })
+        })
+    }
+}
+
+impl Display for PanelEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PanelEvent::Startup { version, model } => {
+                write!(f, "event,startup,{},{}", version, model)
+            }
+            PanelEvent::SleepReached { page } => write!(f, "event,sleepReached,{}", page),
+            PanelEvent::ButtonPress2 {
+                entity,
+                action,
+                value,
+            } => match value {
+                Some(value) => write!(f, "event,buttonPress2,{},{},{}", entity, action, value),
+                None => write!(f, "event,buttonPress2,{},{}", entity, action),
+            },
+            PanelEvent::PageOpenDetail { popup, entity } => {
+                write!(f, "event,pageOpenDetail,{},{}", popup, entity)
+            }
+            PanelEvent::Button { button, action } => {
+                write!(f, r#"{{"Button{}":{{"Action":"{}"}}}}"#, button, action)
+            }
+        }
+    }
+}
+
+impl FromStr for PanelEvent {
+    type Err = ProtocolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unknown = || ProtocolError::UnknownEvent(s.to_string());
+        // The last field (button value) may contain commas
+        let fields: Vec<&str> = s.splitn(5, ',').collect();
+        if fields.len() < 3 || fields[0] != "event" {
+            return Err(unknown());
+        }
+        let field = |i: usize| fields.get(i).map(|f| f.to_string()).ok_or_else(unknown);
+
+        match fields[1] {
+            "startup" => Ok(PanelEvent::Startup {
+                version: field(2)?,
+                model: field(3).unwrap_or_default(),
+            }),
+            "sleepReached" => Ok(PanelEvent::SleepReached { page: field(2)? }),
+            "buttonPress2" => Ok(PanelEvent::ButtonPress2 {
+                entity: field(2)?,
+                action: field(3)?,
+                value: field(4).ok(),
+            }),
+            "pageOpenDetail" => Ok(PanelEvent::PageOpenDetail {
+                popup: field(2)?,
+                entity: field(3)?,
+            }),
+            _ => Err(unknown()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: PanelMessage) {
+        let encoded = message.to_string();
+        assert_eq!(encoded.parse::<PanelMessage>(), Ok(message), "{}", encoded);
+    }
+
+    #[test]
+    fn panel_messages_round_trip() {
+        round_trip(PanelMessage::PageType("cardGrid".into()));
+        round_trip(PanelMessage::EntityUpd {
+            heading: "Home".into(),
+            navigation: NAVIGATION.into(),
+            items: vec!["light".into(), "light.living".into(), "".into()],
+        });
+        round_trip(PanelMessage::EntityUpdateDetail2 {
+            entity: "media_player.kitchen".into(),
+            items: vec!["".into(), "17299".into(), "media_pl".into(), "Radio".into()],
+        });
+        round_trip(PanelMessage::EntityUpdateDetail {
+            entity: "climate.room".into(),
+            items: vec!["Preset".into(), "preset_modes".into(), "eco?comfort".into()],
+        });
+        round_trip(PanelMessage::WeatherUpdate(vec![
+            "".into(),
+            "21.5°C".into(),
+        ]));
+        round_trip(PanelMessage::Color(vec![0, 65535, 31728]));
+        round_trip(PanelMessage::Time {
+            time: "07:05".into(),
+            am_pm: "".into(),
+        });
+        round_trip(PanelMessage::Date("Monday, 01. January 2024".into()));
+        round_trip(PanelMessage::Timeout(35));
+        round_trip(PanelMessage::DimMode {
+            dim: 10,
+            active: 100,
+            background: 6371,
+        });
+        round_trip(PanelMessage::Notify {
+            heading: "Door".into(),
+            text: "Front door is open".into(),
+        });
+        round_trip(PanelMessage::Temperature {
+            icon: "".into(),
+            text: "21.5°C".into(),
+        });
+        round_trip(PanelMessage::Raw("X".into()));
+    }
+
+    #[test]
+    fn panel_message_encoding() {
+        let message = PanelMessage::DimMode {
+            dim: 10,
+            active: 100,
+            background: 6371,
+        };
+        assert_eq!(message.to_string(), "dimmode~10~100~6371");
+        let message = PanelMessage::EntityUpd {
+            heading: "Alarm".into(),
+            navigation: NAVIGATION.into(),
+            items: vec!["alarm_control_panel.alarm".into(), "".into()],
+        };
+        assert_eq!(
+            message.to_string(),
+            "entityUpd~Alarm~1|1~alarm_control_panel.alarm~"
+        );
+    }
+
+    #[test]
+    fn user_text_is_escaped() {
+        let message = PanelMessage::EntityUpd {
+            heading: "Living ~ Kitchen".into(),
+            navigation: NAVIGATION.into(),
+            items: vec!["a~b".into()],
+        };
+        let encoded = message.to_string();
+        assert_eq!(encoded, "entityUpd~Living - Kitchen~1|1~a-b");
+        assert_eq!(
+            encoded.parse::<PanelMessage>(),
+            Ok(PanelMessage::EntityUpd {
+                heading: "Living - Kitchen".into(),
+                navigation: NAVIGATION.into(),
+                items: vec!["a-b".into()],
+            })
+        );
+    }
+
+    fn event_round_trip(event: PanelEvent) {
+        let encoded = event.to_string();
+        assert_eq!(encoded.parse::<PanelEvent>(), Ok(event), "{}", encoded);
+    }
+
+    #[test]
+    fn panel_events_round_trip() {
+        event_round_trip(PanelEvent::Startup {
+            version: "53".into(),
+            model: "eu".into(),
+        });
+        event_round_trip(PanelEvent::SleepReached {
+            page: "cardGrid".into(),
+        });
+        event_round_trip(PanelEvent::ButtonPress2 {
+            entity: "screensaver".into(),
+            action: "bExit".into(),
+            value: Some("1".into()),
+        });
+        event_round_trip(PanelEvent::ButtonPress2 {
+            entity: "cardGrid".into(),
+            action: "bNext".into(),
+            value: None,
+        });
+        event_round_trip(PanelEvent::ButtonPress2 {
+            entity: "light.living".into(),
+            action: "colorWheel".into(),
+            value: Some("168|133|179".into()),
+        });
+        event_round_trip(PanelEvent::PageOpenDetail {
+            popup: "popupThermo".into(),
+            entity: "climate.room".into(),
+        });
+    }
+
+    #[test]
+    fn panel_event_from_payload() {
+        assert_eq!(
+            PanelEvent::from_payload(
+                r#"{"CustomRecv":"event,buttonPress2,alarm_control_panel.alarm,disarm,12,34"}"#
+            ),
+            Ok(PanelEvent::ButtonPress2 {
+                entity: "alarm_control_panel.alarm".into(),
+                action: "disarm".into(),
+                value: Some("12,34".into()),
+            })
+        );
+        assert_eq!(
+            PanelEvent::from_payload(r#"{"Button2":{"Action":"DOUBLE"}}"#),
+            Ok(PanelEvent::Button {
+                button: 2,
+                action: "DOUBLE".into(),
+            })
+        );
+        assert!(matches!(
+            PanelEvent::from_payload(r#"{"StatusSNS":{}}"#),
+            Err(ProtocolError::InvalidPayload(_))
+        ));
+        assert!(matches!(
+            PanelEvent::from_payload(r#"{"CustomRecv":"event,unknown,x"}"#),
+            Err(ProtocolError::UnknownEvent(_))
+        ));
+    }
+}
@@ -0,0 +1,389 @@
+use crate::cards::Card;
+use crate::homeassitant::entities::{self, EntityState};
+use crate::homeassitant::events::EntitiesEvent;
+use crate::protocol::PanelMessage;
+use log::{debug, info};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub(crate) current: Card,
+    pub(crate) previous: Card,
+    /// Index of the last displayed card in `Device::get_cards()`, several cards can have the
+    /// same type.
+    pub(crate) index: usize,
+    /// Index of the displayed sub page for cards with more entities than fit on the screen.
+    pub(crate) sub_page: usize,
+}
+impl Default for Page {
+    fn default() -> Self {
+        Page {
+            current: Card::Screensaver,
+            previous: Card::Screensaver,
+            index: 0,
+            sub_page: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AlarmState {
+    pub(crate) state: String,
+    pub(crate) supported_mode: String,
+    pub(crate) code_arm_required: Option<bool>,
+    pub(crate) entity: String,
+    pub(crate) icon: (String, u32), // (icon, color)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DeviceState {
+    pub(crate) temp: Option<String>,
+    pub(crate) humidity: Option<String>,
+    pub(crate) iaq: Option<String>,
+    pub(crate) page: Option<Page>,
+    pub(crate) alarm: Option<AlarmState>,
+    /// Last known state of the device entities, pages are rendered from it.
+    pub(crate) entities: BTreeMap<String, EntityState>,
+    /// Values of the `input_number` entities used by the brightness schedule.
+    pub(crate) brightness: BTreeMap<String, u16>,
+    /// Screensaver brightness last sent to the panel.
+    pub(crate) dim: Option<u16>,
+    /// Scheduled brightness when `dim` was sent, a different one means a schedule boundary
+    /// was crossed.
+    pub(crate) dim_schedule: Option<u16>,
+    /// Weather update and colors displayed on the screensaver, in this order.
+    pub(crate) weather: Vec<PanelMessage>,
+    /// Reported by the Tasmota LWT, `None` until the panel is heard of.
+    pub(crate) online: Option<bool>,
+    /// Numeric history of the chart entities, as (unix time, value).
+    pub(crate) history: BTreeMap<String, Vec<(f64, f64)>>,
+    /// Unix time of the last history request, by entity.
+    pub(crate) history_requested: BTreeMap<String, i64>,
+    /// Unix time until which a locked card stays unlocked, by card type.
+    pub(crate) unlocked: BTreeMap<String, i64>,
+    /// Locked card waiting for its pin on the `cardUnlock` page.
+    pub(crate) unlock_target: Option<String>,
+    /// Entity of the last detail popup, with the card it was opened on. The popup is updated
+    /// while that card is displayed.
+    pub(crate) detail: Option<(Card, String)>,
+}
+
+impl DeviceState {
+    // Update fields with non-None values from the provided object
+    fn update_from(&mut self, other: DeviceState) {
+        if let Some(temp) = other.temp {
+            self.temp = Some(temp);
+        }
+        if let Some(humidity) = other.humidity {
+            self.humidity = Some(humidity);
+        }
+        if let Some(iaq) = other.iaq {
+            self.iaq = Some(iaq);
+        }
+        if let Some(page) = other.page.clone() {
+            self.page = Some(page);
+        }
+        if let Some(alarm) = other.alarm.clone() {
+            if let Some(stored) = &mut self.alarm {
+                if !alarm.state.is_empty() {
+                    stored.state = alarm.state;
+                }
+                if !alarm.supported_mode.is_empty() {
+                    stored.supported_mode = alarm.supported_mode;
+                }
+                if alarm.code_arm_required.is_some() {
+                    stored.code_arm_required = alarm.code_arm_required;
+                }
+                if !alarm.icon.0.is_empty() {
+                    stored.icon = alarm.icon;
+                }
+                if !alarm.entity.is_empty() {
+                    stored.entity = alarm.entity;
+                }
+            } else {
+                self.alarm = Some(alarm);
+            }
+        }
+        self.entities.extend(other.entities);
+
+        self.brightness.extend(other.brightness);
+        if let Some(dim) = other.dim {
+            self.dim = Some(dim);
+        }
+        if let Some(dim_schedule) = other.dim_schedule {
+            self.dim_schedule = Some(dim_schedule);
+        }
+        if !other.weather.is_empty() {
+            self.weather = other.weather;
+        }
+        if let Some(online) = other.online {
+            self.online = Some(online);
+        }
+        self.history.extend(other.history);
+        self.history_requested.extend(other.history_requested);
+        self.unlocked.extend(other.unlocked);
+        if let Some(unlock_target) = other.unlock_target {
+            self.unlock_target = Some(unlock_target);
+        }
+        if let Some(detail) = other.detail {
+            self.detail = Some(detail);
+        }
+    }
+
+    /// New device state, displaying the default page.
+    fn with_default_page() -> Self {
+        DeviceState {
+            page: Some(Page::default()),
+            ..Default::default()
+        }
+    }
+}
+
+/// State of all devices, owned by the runtime and shared between the Mqtt tasks.
+/// A new configuration is starting from an empty store, see `StateStore::reset()`.
+#[derive(Debug, Clone, Default)]
+pub struct StateStore {
+    devices: Arc<RwLock<HashMap<String, DeviceState>>>,
+}
+
+impl StateStore {
+    pub fn new() -> Self {
+        StateStore::default()
+    }
+
+    /// Merge the provided state with the stored one, see `DeviceState::update_from()`.
+    pub fn update(&self, device_id: &str, new_state: DeviceState) {
+        let mut devices = self.write();
+        match devices.get_mut(device_id) {
+            Some(state) => {
+                debug!("Current {} device state {:?}", device_id, state);
+                state.update_from(new_state);
+                debug!("New {} device state {:?}", device_id, state);
+            }
+            None => {
+                info!(
+                    "Provided device_id: [{}] was not found! Creating new record.",
+                    device_id
+                );
+                let mut state = new_state;
+                state.page = Some(Page::default()); //Making default page
+                devices.insert(device_id.to_string(), state);
+            }
+        }
+    }
+
+    pub fn get(&self, device_id: &str) -> DeviceState {
+        self.devices
+            .read()
+            .expect("Failed to acquire read lock on StateStore: Lock is poisoned!")
+            .get(device_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Panels are considered online until Tasmota reports them offline.
+    pub fn is_online(&self, device_id: &str) -> bool {
+        self.get(device_id).online != Some(false)
+    }
+
+    /// Forget the detail popup, `StateStore::update()` can only set it.
+    pub fn clear_detail(&self, device_id: &str) {
+        if let Some(device_state) = self.write().get_mut(device_id) {
+            device_state.detail = None;
+        }
+    }
+
+    /// Forget the locked card waiting for its pin, `StateStore::update()` can only set it.
+    pub fn clear_unlock_target(&self, device_id: &str) {
+        if let Some(device_state) = self.write().get_mut(device_id) {
+            device_state.unlock_target = None;
+        }
+    }
+
+    /// Apply the Hass `subscribe_entities` event on the device entities.
+    /// Returns the new state of the added and changed entities.
+    pub fn apply_entities(
+        &self,
+        device_id: &str,
+        event: EntitiesEvent,
+    ) -> BTreeMap<String, EntityState> {
+        let mut devices = self.write();
+        let device_state = devices
+            .entry(device_id.to_string())
+            .or_insert_with(DeviceState::with_default_page);
+        entities::apply_event(&mut device_state.entities, event)
+    }
+
+    /// Add the Hass `get_states` states that are not yet known by the device.
+    /// Returns the added entities.
+    pub fn apply_states(
+        &self,
+        device_id: &str,
+        states: BTreeMap<String, EntityState>,
+    ) -> BTreeMap<String, EntityState> {
+        let mut devices = self.write();
+        let device_state = devices
+            .entry(device_id.to_string())
+            .or_insert_with(DeviceState::with_default_page);
+        let missing: BTreeMap<String, EntityState> = states
+            .into_iter()
+            .filter(|(entity, _)| !device_state.entities.contains_key(entity))
+            .collect();
+        device_state.entities.extend(missing.clone());
+        missing
+    }
+
+    /// Forget the state of all devices, used when the configuration is reloaded.
+    pub fn reset(&self) {
+        self.write().clear();
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, HashMap<String, DeviceState>> {
+        self.devices
+            .write()
+            .expect("Failed to acquire write lock on StateStore: Lock is poisoned!")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::homeassitant::events::{CompressedChange, CompressedDiff, CompressedState};
+
+    #[test]
+    fn devices_are_isolated() {
+        let store = StateStore::new();
+        store.update(
+            "panel-1",
+            DeviceState {
+                temp: Some("21.5".into()),
+                weather: vec![PanelMessage::Color(vec![1, 2])],
+                ..Default::default()
+            },
+        );
+        store.update(
+            "panel-2",
+            DeviceState {
+                weather: vec![PanelMessage::Color(vec![3])],
+                ..Default::default()
+            },
+        );
+
+        let panel_1 = store.get("panel-1");
+        assert_eq!(panel_1.temp.as_deref(), Some("21.5"));
+        assert_eq!(panel_1.weather, vec![PanelMessage::Color(vec![1, 2])]);
+        assert_eq!(panel_1.page.map(|p| p.current), Some(Card::Screensaver));
+        assert_eq!(store.get("panel-2").temp, None);
+        assert_eq!(
+            store.get("panel-2").weather,
+            vec![PanelMessage::Color(vec![3])]
+        );
+    }
+
+    #[test]
+    fn update_keeps_unset_fields() {
+        let store = StateStore::new();
+        store.update(
+            "panel",
+            DeviceState {
+                temp: Some("20".into()),
+                dim: Some(10),
+                ..Default::default()
+            },
+        );
+        store.update(
+            "panel",
+            DeviceState {
+                dim: Some(1),
+                ..Default::default()
+            },
+        );
+
+        let state = store.get("panel");
+        assert_eq!(state.temp.as_deref(), Some("20"));
+        assert_eq!(state.dim, Some(1));
+    }
+
+    #[test]
+    fn entities_are_cached() {
+        let store = StateStore::new();
+        let snapshot = EntitiesEvent {
+            added: BTreeMap::from([(
+                "light.room".to_string(),
+                CompressedState {
+                    state: "off".into(),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        store.apply_entities("panel", snapshot);
+        let change = EntitiesEvent {
+            changed: BTreeMap::from([(
+                "light.room".to_string(),
+                CompressedDiff {
+                    additions: CompressedChange {
+                        state: Some("on".into()),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let updated = store.apply_entities("panel", change);
+        assert_eq!(updated["light.room"].state, "on");
+
+        // `get_states` is not overriding the subscription
+        let fallback = BTreeMap::from([
+            (
+                "light.room".to_string(),
+                EntityState {
+                    state: "off".into(),
+                    ..Default::default()
+                },
+            ),
+            (
+                "switch.fan".to_string(),
+                EntityState {
+                    state: "on".into(),
+                    ..Default::default()
+                },
+            ),
+        ]);
+        let added = store.apply_states("panel", fallback);
+        assert_eq!(added.keys().collect::<Vec<_>>(), vec!["switch.fan"]);
+        assert_eq!(store.get("panel").entities["light.room"].state, "on");
+    }
+
+    #[test]
+    fn detail_is_cleared() {
+        let store = StateStore::new();
+        store.update(
+            "panel",
+            DeviceState {
+                detail: Some((Card::CardGrid, "light.room".into())),
+                unlock_target: Some("cardAlarm".into()),
+                ..Default::default()
+            },
+        );
+        // Unset fields are not clearing the stored ones
+        store.update("panel", DeviceState::default());
+        assert!(store.get("panel").detail.is_some());
+
+        store.clear_detail("panel");
+        store.clear_unlock_target("panel");
+        let state = store.get("panel");
+        assert_eq!(state.detail, None);
+        assert_eq!(state.unlock_target, None);
+    }
+
+    #[test]
+    fn reset_forgets_all_devices() {
+        let store = StateStore::new();
+        store.update("panel", DeviceState::default());
+        store.reset();
+        assert!(store.get("panel").page.is_none());
+    }
+}
@@ -1,12 +1,10 @@
 use indexmap::IndexMap;
 use lazy_static::lazy_static;
-use log::{debug, info};
 
-use crate::cards::Card;
+use crate::protocol::PanelMessage;
 use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
 use std::string::ToString;
-use std::sync::{Arc, RwLock};
 
 /// Hide sensitive data from logs based on regex pattern
 pub fn redact<'a>(string: &'a str, regex: &'a str) -> Cow<'a, str> {
@@ -27,13 +25,7 @@ pub fn redact<'a>(string: &'a str, regex: &'a str) -> Cow<'a, str> {
     res
 }
 
-pub const WEATHER_KEY: &str = "weather";
-pub const WEATHER_COLORS_KEY: &str = "weather_colors";
-
 lazy_static! {
-    pub static ref STORED_STATE: Arc<RwLock<HashMap<String, String>>> =  Arc::new(RwLock::new(HashMap::new()));
-    static ref DEVICE_STATE: Arc<RwLock<HashMap<String, DeviceState>>> =  Arc::new(RwLock::new(HashMap::new()));
-
     pub static ref WEATHER_COLORS: HashMap<String, u32> =
         HashMap::from([
         //#50% grey
@@ -120,7 +112,7 @@ pub fn get_weather_icon(state: String, icons: &BTreeMap<String, char>) -> char {
     return '\0';
 }
 
-pub fn get_screensaver_color_output(icons: HashMap<String, String>) -> String {
+pub fn get_screensaver_color_output(icons: HashMap<String, String>) -> PanelMessage {
     let keys = [
         "tMainIcon".to_string(),
         "tF1Icon".to_string(),
@@ -128,138 +120,18 @@ pub fn get_screensaver_color_output(icons: HashMap<String, String>) -> String {
         "tF3Icon".to_string(),
         "tF4Icon".to_string(),
     ];
-    let mut color_output = "color".to_string();
-    for (key, value) in DEFAULT_SCREENSAVER_COLOR_MAPPING.iter() {
-        if keys.contains(key) {
-            if let Some(weather) = icons.get(key) {
-                if let Some(color) = WEATHER_COLORS.get(weather) {
-                    color_output += &*format!("~{}", color);
-                } else {
-                    color_output += &*format!("~{}", value);
-                }
-            } else {
-                color_output += &*format!("~{}", value);
-            }
-        } else {
-            color_output += &*format!("~{}", value);
-        }
-    }
-    color_output
-}
-#[derive(Debug, Clone)]
-pub struct Page {
-    pub(crate) current: Card,
-    pub(crate) previous: Card,
-}
-impl Default for Page {
-    fn default() -> Self {
-        Page {
-            current: Card::Screensaver,
-            previous: Card::Screensaver,
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
-pub struct AlarmState {
-    pub(crate) state: String,
-    pub(crate) supported_mode: String,
-    pub(crate) code_arm_required: Option<bool>,
-    pub(crate) entity: String,
-    pub(crate) icon: (String, u32), // (icon, color)
-}
-
-#[derive(Debug, Clone, Default)]
-pub struct DeviceState {
-    pub(crate) temp: Option<String>,
-    pub(crate) humidity: Option<String>,
-    pub(crate) iaq: Option<String>,
-    pub(crate) page: Option<Page>,
-    pub(crate) alarm: Option<AlarmState>,
-}
-
-impl DeviceState {
-    // Update fields with non-None values from the provided object
-    fn update_from(&mut self, other: DeviceState) {
-        if let Some(temp) = other.temp {
-            self.temp = Some(temp);
-        }
-        if let Some(humidity) = other.humidity {
-            self.humidity = Some(humidity);
-        }
-        if let Some(iaq) = other.iaq {
-            self.iaq = Some(iaq);
-        }
-        if let Some(page) = other.page.clone() {
-            self.page = Some(page);
-        }
-        if let Some(alarm) = other.alarm.clone() {
-            if let Some(stored) = &mut self.alarm {
-                if !alarm.state.is_empty() {
-                    stored.state = alarm.state;
-                }
-                if !alarm.supported_mode.is_empty() {
-                    stored.supported_mode = alarm.supported_mode;
-                }
-                if alarm.code_arm_required.is_some() {
-                    stored.code_arm_required = alarm.code_arm_required;
-                }
-                if !alarm.icon.0.is_empty() {
-                    stored.icon = alarm.icon;
-                }
-                if !alarm.entity.is_empty() {
-                    stored.entity = alarm.entity;
-                }
+    let colors = DEFAULT_SCREENSAVER_COLOR_MAPPING
+        .iter()
+        .map(|(key, value)| {
+            if keys.contains(key) {
+                icons
+                    .get(key)
+                    .and_then(|weather| WEATHER_COLORS.get(weather))
+                    .map_or(*value, |color| *color)
             } else {
-                self.alarm = Some(alarm);
+                *value
             }
-        }
-    }
-
-    // Read, model, and then overwrite the value
-    pub fn read_process_overwrite(key: &str, new_state: DeviceState) {
-        // Read the current value
-        let current_value = {
-            let read_lock = DEVICE_STATE
-                .read()
-                .expect("Failed to acquire read lock on DEVICE_STATE: Lock is poisoned!");
-            read_lock.get(key).cloned()
-        };
-        let mut device_state: DeviceState;
-        // Process the current value (if needed)
-        if let Some(mut state) = current_value {
-            debug!("Current {} device state {:?}", key, state);
-            // Overwrite the value
-            state.update_from(new_state);
-            device_state = state;
-            debug!("New {} device state {:?}", key, device_state);
-        } else {
-            info!(
-                "Provided device_id: [{}] was not found! Creating new record.",
-                key
-            );
-            device_state = new_state;
-            device_state.page = Some(Page::default()); //Making default page
-        }
-
-        let mut write_lock = DEVICE_STATE
-            .write()
-            .expect("Failed to acquire write lock on DEVICE_STATE: Lock is poisoned!");
-        write_lock.insert(key.to_string(), device_state);
-    }
-
-    pub fn get_state(id: &str) -> DeviceState {
-        // Read the current value
-        let current_value = {
-            let read_lock = DEVICE_STATE
-                .read()
-                .expect("Failed to acquire read lock on DEVICE_STATE: Lock is poisoned!");
-            read_lock.get(id).cloned()
-        };
-        if let Some(state) = current_value {
-            state
-        } else {
-            DeviceState::default()
-        }
-    }
+        })
+        .collect();
+    PanelMessage::Color(colors)
 }